@@ -1,11 +1,12 @@
 use std::sync::Arc;
 use once_cell::sync::OnceCell;
-use serde_json::Value;
-use serde::Serialize;
-use crate::utilities::read_json_file;
+use serde_json::{Map, Value};
+use serde::{Serialize, Deserialize};
+use crate::utilities::{read_json_file, write_file_atomic};
+use crate::storage::DEFAULT_STORAGE_MAX_BYTES;
 use std::path::PathBuf;
-use std::fs::File;
-use std::io::Write;
+use std::fs;
+use std::collections::HashMap;
 
 pub struct AppConstants {
     pub tauri_config : Arc<tauri::Config>,
@@ -13,16 +14,161 @@ pub struct AppConstants {
 }
 pub static APP_CONSTANTS: OnceCell<AppConstants> = OnceCell::new();
 
-#[derive(Serialize)]
+/// The boot config schema version written by this build. Bump this and append a step to
+/// `MIGRATIONS` whenever the on-disk layout changes.
+pub const BOOT_CONFIG_VERSION: u32 = 2;
+
+pub const DEFAULT_PERSISTED_STORAGE_PREFIX: &str = "persisted.";
+
+pub const DEFAULT_TRUSTED_ORIGINS: &[&str] = &["https://phcode.dev/"];
+
+/// The release stage in which pages on localhost may call commands, see ipc_security.rs.
+pub const DEV_STAGE: &str = "dev";
+
+/// Window label whose state was stored in the flat `last_window_*` fields of v1 configs.
+pub const MAIN_WINDOW_LABEL: &str = "main";
+
+/// Saved states past this many are pruned, least recently saved first. Window labels can be
+/// unique per project, so the map would otherwise grow with every project ever opened.
+pub const MAX_SAVED_WINDOWS: usize = 32;
+
+/// Saved geometry of a single window. Position and size are in physical pixels of the monitor
+/// the window was on.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct WindowState {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub maximized: bool,
+    pub fullscreen: bool,
+    pub monitor: Option<String>,
+    pub monitor_position: Option<(i32, i32)>,
+    /// 0 when unknown.
+    pub monitor_scale_factor: f64,
+    /// Webview zoom set with `zoom_window`, None if the window was never zoomed.
+    pub zoom_level: Option<f64>,
+    pub devtools_open: bool,
+    /// Unix time in milliseconds when the state was saved, used to prune old windows.
+    pub saved_at: u64,
+}
+
+/// Where crash reports go, see crash_report.rs.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum CrashReportSinkKind {
+    /// Kept as files in app_local_data_dir, never sent.
+    #[default]
+    LocalFile,
+    /// Bugsnag's error reporting api, or a server compatible with it.
+    Bugsnag,
+    /// A Sentry envelope posted to any http endpoint, like a self hosted Sentry or collector.
+    Http,
+}
+
+/// Crash reporting is off unless turned on here.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CrashReportingConfig {
+    pub enabled: bool,
+    pub sink: CrashReportSinkKind,
+    /// Url reports are posted to. Required for the http sink, the bugsnag sink defaults to
+    /// Bugsnag's notify url.
+    pub endpoint: Option<String>,
+    /// Bugsnag api key, or the Sentry public key for the http sink. Required for bugsnag.
+    pub api_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct BootConfig {
     pub version: u32,
-    pub last_window_x: i32,
-    pub last_window_y: i32,
-    pub last_window_width: u32,
-    pub last_window_height: u32,
-    pub last_window_maximized: bool,
+    /// Saved state of every window that was open, keyed by window label.
+    pub windows: HashMap<String, WindowState>,
+    /// `Storage` keys starting with this prefix are persisted across restarts.
+    pub persisted_storage_prefix: String,
+    /// Byte budget of the in memory `Storage`, 0 for unlimited.
+    pub storage_max_bytes: usize,
+    /// Url prefixes, besides the app itself, whose pages may call commands. See ipc_security.rs.
+    pub trusted_origins: Vec<String>,
+    /// Release stage, "dev", "stage" or "production" like `config.environment` of the phoenix
+    /// build. Unset for the stage of this build, see `release_stage`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stage: Option<String>,
+    pub crash_reporting: CrashReportingConfig,
+    /// Fields this build doesn't know, like those added by newer versions. Kept so that writing
+    /// the config back doesn't drop them.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            version: BOOT_CONFIG_VERSION,
+            windows: HashMap::new(),
+            persisted_storage_prefix: DEFAULT_PERSISTED_STORAGE_PREFIX.to_string(),
+            storage_max_bytes: DEFAULT_STORAGE_MAX_BYTES,
+            trusted_origins: DEFAULT_TRUSTED_ORIGINS.iter().map(|origin| origin.to_string()).collect(),
+            stage: None,
+            crash_reporting: CrashReportingConfig::default(),
+            extra: Map::new(),
+        }
+    }
 }
+
+/// The stage of this build: dev for debug builds, production otherwise.
+pub fn build_stage() -> &'static str {
+    if cfg!(debug_assertions) { DEV_STAGE } else { "production" }
+}
+
+impl BootConfig {
+    /// `stage`, or the stage of this build if it isn't set.
+    pub fn release_stage(&self) -> &str {
+        self.stage.as_deref().unwrap_or(build_stage())
+    }
+}
+
 static BOOT_CONFIG_FILE_NAME: &'static str = "boot_config.json";
+static BOOT_CONFIG_BACKUP_FILE_NAME: &'static str = "boot_config.json.bak";
+
+/// A migration step upgrades the raw json of a config from version `n` to `n + 1`, where `n` is
+/// the index of the step in `MIGRATIONS`.
+type Migration = fn(&mut Value);
+
+static MIGRATIONS: &[Migration] = &[
+    _migrate_v0_to_v1,
+    _migrate_v1_to_v2,
+];
+
+// Configs written before versioning have the same fields as v1.
+fn _migrate_v0_to_v1(_value: &mut Value) {
+}
+
+// v1 only tracked the main window in flat `last_window_*` fields. v2 keeps a map of windows.
+fn _migrate_v1_to_v2(value: &mut Value) {
+    let Some(config) = value.as_object_mut() else {
+        return;
+    };
+    let mut main_window = Map::new();
+    for (old_key, new_key) in [
+        ("last_window_x", "x"),
+        ("last_window_y", "y"),
+        ("last_window_width", "width"),
+        ("last_window_height", "height"),
+        ("last_window_maximized", "maximized"),
+    ] {
+        if let Some(field) = config.remove(old_key) {
+            main_window.insert(new_key.to_string(), field);
+        }
+    }
+    let mut windows = Map::new();
+    if !main_window.is_empty() {
+        windows.insert(MAIN_WINDOW_LABEL.to_string(), Value::Object(main_window));
+    }
+    config.insert("windows".to_string(), Value::Object(windows));
+}
 
 fn get_boot_config_file_path(app_local_data_dir: &PathBuf) -> PathBuf {
     let mut config_file_path = app_local_data_dir.clone();
@@ -30,75 +176,245 @@ fn get_boot_config_file_path(app_local_data_dir: &PathBuf) -> PathBuf {
     return config_file_path;
 }
 
-fn _set_boot_config(boot_config: &mut BootConfig, value: &Value) {
-    boot_config.version = match value["version"].as_u64() {
-        Some(value) => value as u32,
-        None => 0
-    };
-    boot_config.last_window_x = match value["last_window_x"].as_i64() {
-        Some(v) => v as i32,
-        None => 0
-    };
-    boot_config.last_window_y = match value["last_window_y"].as_i64() {
-        Some(v) => v as i32,
-        None => 0
-    };
-    boot_config.last_window_width = match value["last_window_width"].as_u64() {
-        Some(v) => v as u32,
-        None => 0
-    };
-    boot_config.last_window_height = match value["last_window_height"].as_u64() {
-        Some(v) => v as u32,
-        None => 0
-    };
-    boot_config.last_window_maximized = match value["last_window_maximized"].as_bool() {
-        Some(v) => v,
-        None => false
-    };
+fn get_boot_config_backup_file_path(app_local_data_dir: &PathBuf) -> PathBuf {
+    let mut config_file_path = app_local_data_dir.clone();
+    config_file_path.push(BOOT_CONFIG_BACKUP_FILE_NAME);
+    return config_file_path;
+}
+
+/// Runs the migration chain on a raw config value and deserializes it. Missing fields fall
+/// back to their defaults and unknown fields end up in `extra`. A config written by a newer
+/// build keeps its version, see `update_boot_config`.
+fn _parse_boot_config(mut value: Value) -> Option<BootConfig> {
+    if !value.is_object() {
+        return None;
+    }
+    let mut version = value["version"].as_u64().unwrap_or(0) as usize;
+    if version > BOOT_CONFIG_VERSION as usize {
+        // written by a newer build. Read what we understand, the rest is kept in `extra`.
+        eprintln!("boot config version {} is newer than supported version {}", version, BOOT_CONFIG_VERSION);
+    }
+    while version < MIGRATIONS.len() {
+        MIGRATIONS[version](&mut value);
+        version += 1;
+    }
+    value["version"] = Value::from(version);
+    match serde_json::from_value::<BootConfig>(value) {
+        Ok(boot_config) => Some(boot_config),
+        Err(e) => {
+            eprintln!("Failed to parse boot config: {}", e);
+            None
+        }
+    }
+}
+
+/// Reads the config in `app_local_data_dir`, falling back to the `.bak` copy if the main file
+/// is missing or corrupt. The flag tells if the main file parsed fine.
+fn _load_boot_config(app_local_data_dir: &PathBuf) -> (BootConfig, bool) {
+    let boot_config_file_path = get_boot_config_file_path(app_local_data_dir);
+    if let Some(boot_config) = read_json_file(&boot_config_file_path).and_then(_parse_boot_config) {
+        return (boot_config, true);
+    }
+    eprintln!("No valid boot restore config file found {}", boot_config_file_path.display());
+    let backup_file_path = get_boot_config_backup_file_path(app_local_data_dir);
+    if let Some(boot_config) = read_json_file(&backup_file_path).and_then(_parse_boot_config) {
+        println!("Restored boot config from backup {}", backup_file_path.display());
+        return (boot_config, false);
+    }
+    (BootConfig::default(), false)
 }
 
 pub fn read_boot_config() -> BootConfig {
-    let mut boot_config = BootConfig {
-        version: 1,
-        last_window_x: 0,
-        last_window_y: 0,
-        last_window_width: 0,
-        last_window_height: 0,
-        last_window_maximized: false,
-    };
-    if let Some(app_constants) = APP_CONSTANTS.get() {
-        let boot_config_file_path = get_boot_config_file_path(&app_constants.app_local_data_dir);
-        match read_json_file(&boot_config_file_path) {
-            Some(value) =>{
-                _set_boot_config(&mut boot_config, &value);
-            }
-            None => {
-                eprintln!("No boot restore config file found {}", boot_config_file_path.display());
-            }
+    match APP_CONSTANTS.get() {
+        Some(app_constants) => _load_boot_config(&app_constants.app_local_data_dir).0,
+        None => BootConfig::default(),
+    }
+}
+
+/// Writes the config with a temp file + fsync + rename so that a crash or a full disk never
+/// leaves a truncated config behind. If `backup_current` the current file, which parsed fine,
+/// is kept as `.bak` first.
+fn _write_boot_config(app_local_data_dir: &PathBuf, boot_config: &BootConfig, backup_current: bool) -> Result<(), String> {
+    let boot_config_file_path = get_boot_config_file_path(app_local_data_dir);
+    let backup_file_path = get_boot_config_backup_file_path(app_local_data_dir);
+    let json_string = serde_json::to_string(boot_config).map_err(|e| e.to_string())?;
+
+    if backup_current {
+        if let Err(e) = fs::copy(&boot_config_file_path, &backup_file_path) {
+            eprintln!("Failed to back up boot config: {}", e);
         }
     }
-    return boot_config;
+    write_file_atomic(&boot_config_file_path, json_string.as_bytes()).map_err(|e| e.to_string())
 }
 
-fn _write_boot_config(boot_config: &BootConfig) {
-    if let Some(app_constants) = APP_CONSTANTS.get() {
-        let boot_config_file_path = get_boot_config_file_path(&app_constants.app_local_data_dir);
-        // Convert the BootConfig struct to JSON
-        let json_string = serde_json::to_string(boot_config).unwrap();
-        let mut file = File::create(boot_config_file_path).expect("Failed to create file");
-        file.write_all(json_string.as_bytes())
-            .expect("Failed to write to boot config file");   
+fn _update_boot_config_in<F: FnOnce(&mut BootConfig)>(app_local_data_dir: &PathBuf, update: F) -> Result<(), String> {
+    let (mut boot_config, main_file_valid) = _load_boot_config(app_local_data_dir);
+    if boot_config.version > BOOT_CONFIG_VERSION {
+        // this build can't write the newer layout, and writing the old one would break it
+        return Err(format!("boot config version {} is newer than supported version {}, not updating it",
+                           boot_config.version, BOOT_CONFIG_VERSION));
     }
+    update(&mut boot_config);
+    boot_config.version = BOOT_CONFIG_VERSION;
+    _write_boot_config(app_local_data_dir, &boot_config, main_file_valid)
 }
 
+/// Reads the current config, lets `update` modify it and writes it back. Fields that the
+/// caller does not touch, including unknown ones, are preserved. A config written by a newer
+/// build is left alone. Errors are logged and never panic, as this runs during window close.
 // WARNING: If there are multiple windows, this will be called on each window close.
-pub fn write_boot_config(version: u32, x: i32, y: i32, width: u32, height: u32, maximized: bool) {
-    _write_boot_config(&BootConfig {
-        version,
-        last_window_x: x,
-        last_window_y: y,
-        last_window_width: width,
-        last_window_height: height,
-        last_window_maximized: maximized,
-    })
-}
\ No newline at end of file
+pub fn update_boot_config<F: FnOnce(&mut BootConfig)>(update: F) {
+    let Some(app_constants) = APP_CONSTANTS.get() else {
+        eprintln!("Failed to write boot config: App constants not initialized");
+        return;
+    };
+    if let Err(e) = _update_boot_config_in(&app_constants.app_local_data_dir, update) {
+        eprintln!("Failed to write boot config: {}", e);
+    }
+}
+
+// Drops the least recently saved windows past `MAX_SAVED_WINDOWS`. The main window is kept.
+fn _prune_saved_windows(windows: &mut HashMap<String, WindowState>) {
+    while windows.len() > MAX_SAVED_WINDOWS {
+        let oldest = windows.iter()
+            .filter(|(label, _)| label.as_str() != MAIN_WINDOW_LABEL)
+            .min_by_key(|(_, window_state)| window_state.saved_at)
+            .map(|(label, _)| label.clone());
+        match oldest {
+            Some(label) => windows.remove(&label),
+            None => break,
+        };
+    }
+}
+
+/// Records the state of one window, leaving the saved state of every other window untouched
+/// apart from pruning the oldest ones.
+pub fn write_window_state(window_label: &str, window_state: WindowState) {
+    update_boot_config(|boot_config| {
+        boot_config.windows.insert(window_label.to_string(), window_state);
+        _prune_saved_windows(&mut boot_config.windows);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::test_dir;
+    use serde_json::json;
+
+    #[test]
+    fn migrates_unversioned_config_to_windows_map() {
+        let boot_config = _parse_boot_config(json!({
+            "last_window_x": 10,
+            "last_window_y": -20,
+            "last_window_width": 800,
+            "last_window_height": 600,
+            "last_window_maximized": true,
+        })).unwrap();
+        assert_eq!(boot_config.version, BOOT_CONFIG_VERSION);
+        let main = &boot_config.windows[MAIN_WINDOW_LABEL];
+        assert_eq!((main.x, main.y, main.width, main.height, main.maximized), (10, -20, 800, 600, true));
+        assert!(boot_config.extra.is_empty());
+    }
+
+    #[test]
+    fn migrates_v1_config() {
+        let boot_config = _parse_boot_config(json!({
+            "version": 1,
+            "last_window_width": 1024,
+            "persisted_storage_prefix": "keep.",
+        })).unwrap();
+        assert_eq!(boot_config.version, BOOT_CONFIG_VERSION);
+        assert_eq!(boot_config.windows[MAIN_WINDOW_LABEL].width, 1024);
+        assert_eq!(boot_config.persisted_storage_prefix, "keep.");
+
+        let boot_config = _parse_boot_config(json!({ "version": 1 })).unwrap();
+        assert!(boot_config.windows.is_empty());
+    }
+
+    #[test]
+    fn current_config_is_unchanged() {
+        let boot_config = _parse_boot_config(json!({
+            "version": 2,
+            "windows": { "editor": { "width": 640, "zoom_level": 1.5 } },
+        })).unwrap();
+        assert_eq!(boot_config.windows["editor"].width, 640);
+        assert_eq!(boot_config.windows["editor"].zoom_level, Some(1.5));
+        assert_eq!(boot_config.storage_max_bytes, DEFAULT_STORAGE_MAX_BYTES);
+    }
+
+    #[test]
+    fn rejects_non_object() {
+        assert!(_parse_boot_config(json!([1, 2])).is_none());
+        assert!(_parse_boot_config(json!({ "version": 2, "windows": 3 })).is_none());
+    }
+
+    #[test]
+    fn unknown_fields_survive_a_rewrite() {
+        let dir = test_dir("boot-config-unknown-fields");
+        fs::write(get_boot_config_file_path(&dir),
+                  json!({ "version": 2, "future_setting": { "a": 1 } }).to_string()).unwrap();
+        _update_boot_config_in(&dir, |boot_config| {
+            boot_config.windows.insert("main".to_string(), WindowState { width: 5, ..Default::default() });
+        }).unwrap();
+        let written = read_json_file(&get_boot_config_file_path(&dir)).unwrap();
+        assert_eq!(written["future_setting"], json!({ "a": 1 }));
+        assert_eq!(written["windows"]["main"]["width"], 5);
+        assert!(get_boot_config_backup_file_path(&dir).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newer_config_is_read_but_not_rewritten() {
+        let dir = test_dir("boot-config-newer-version");
+        let contents = json!({
+            "version": 99,
+            "windows": { "main": { "width": 700 } },
+            "new_layout": true,
+        }).to_string();
+        fs::write(get_boot_config_file_path(&dir), &contents).unwrap();
+
+        let (boot_config, main_file_valid) = _load_boot_config(&dir);
+        assert!(main_file_valid);
+        assert_eq!(boot_config.version, 99);
+        assert_eq!(boot_config.windows["main"].width, 700);
+        assert_eq!(boot_config.extra["new_layout"], json!(true));
+
+        assert!(_update_boot_config_in(&dir, |boot_config| boot_config.windows.clear()).is_err());
+        assert_eq!(fs::read_to_string(get_boot_config_file_path(&dir)).unwrap(), contents);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_least_recently_saved_windows() {
+        let mut windows = HashMap::new();
+        windows.insert(MAIN_WINDOW_LABEL.to_string(), WindowState { saved_at: 0, ..Default::default() });
+        for index in 0..MAX_SAVED_WINDOWS + 5 {
+            windows.insert(format!("project-{}", index), WindowState { saved_at: 100 + index as u64, ..Default::default() });
+        }
+        _prune_saved_windows(&mut windows);
+        assert_eq!(windows.len(), MAX_SAVED_WINDOWS);
+        assert!(windows.contains_key(MAIN_WINDOW_LABEL));
+        for index in 0..6 {
+            assert!(!windows.contains_key(&format!("project-{}", index)));
+        }
+        assert!(windows.contains_key(&format!("project-{}", MAX_SAVED_WINDOWS + 4)));
+    }
+
+    #[test]
+    fn corrupt_config_falls_back_to_backup() {
+        let dir = test_dir("boot-config-backup");
+        fs::write(get_boot_config_file_path(&dir), "{ truncated").unwrap();
+        fs::write(get_boot_config_backup_file_path(&dir),
+                  json!({ "version": 2, "storage_max_bytes": 42 }).to_string()).unwrap();
+        let (boot_config, main_file_valid) = _load_boot_config(&dir);
+        assert!(!main_file_valid);
+        assert_eq!(boot_config.storage_max_bytes, 42);
+
+        // the corrupt file must not replace the good backup
+        _update_boot_config_in(&dir, |_| {}).unwrap();
+        let backup = read_json_file(&get_boot_config_backup_file_path(&dir)).unwrap();
+        assert_eq!(backup["storage_max_bytes"], 42);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+// Breadcrumbs: the last things that happened before a crash, sent along with crash reports.
+// Kept in a bounded ring buffer, the oldest are dropped first. Recorded automatically for
+// command invocations (name only), pages loading in windows, windows closing, phtauri 404s (url
+// without query) and console_error calls (without the message).
+
+use chrono::prelude::*;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+const MAX_BREADCRUMBS: usize = 100;
+/// Longest metadata value kept, longer ones are cut.
+const MAX_METADATA_VALUE_CHARS: usize = 500;
+
+/// The breadcrumb types of the Bugsnag api, which other sinks map from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BreadcrumbType {
+    Navigation,
+    Request,
+    Process,
+    Log,
+    User,
+    State,
+    Error,
+    Manual,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Breadcrumb {
+    /// ISO 8601.
+    pub timestamp: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub breadcrumb_type: BreadcrumbType,
+    pub meta_data: BTreeMap<String, String>,
+}
+
+static BREADCRUMBS: Lazy<Mutex<VecDeque<Breadcrumb>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+// Labels of the windows that have a page loaded and weren't closed yet.
+static OPEN_WINDOWS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+fn truncate(value: &str) -> String {
+    match value.char_indices().nth(MAX_METADATA_VALUE_CHARS) {
+        Some((cut, _)) => format!("{}...", &value[..cut]),
+        None => value.to_string(),
+    }
+}
+
+pub fn leave(breadcrumb_type: BreadcrumbType, name: &str) {
+    leave_with_metadata(breadcrumb_type, name, &[]);
+}
+
+pub fn leave_with_metadata(breadcrumb_type: BreadcrumbType, name: &str, meta_data: &[(&str, &str)]) {
+    let breadcrumb = Breadcrumb {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        name: name.to_string(),
+        breadcrumb_type,
+        meta_data: meta_data.iter().map(|(key, value)| (key.to_string(), truncate(value))).collect(),
+    };
+    let mut breadcrumbs = BREADCRUMBS.lock().unwrap_or_else(|e| e.into_inner());
+    if breadcrumbs.len() == MAX_BREADCRUMBS {
+        breadcrumbs.pop_front();
+    }
+    breadcrumbs.push_back(breadcrumb);
+}
+
+/// Records a page load, and the window opening if it is its first page.
+pub fn page_loaded(window_label: &str) {
+    let opened = OPEN_WINDOWS.lock().unwrap_or_else(|e| e.into_inner()).insert(window_label.to_string());
+    if opened {
+        leave_with_metadata(BreadcrumbType::State, "Window opened", &[("window", window_label)]);
+    }
+    leave_with_metadata(BreadcrumbType::Navigation, "Page loaded", &[("window", window_label)]);
+}
+
+pub fn window_closed(window_label: &str) {
+    OPEN_WINDOWS.lock().unwrap_or_else(|e| e.into_inner()).remove(window_label);
+    leave_with_metadata(BreadcrumbType::State, "Window closed", &[("window", window_label)]);
+}
+
+// The snapshots below run in the panic hook. They don't wait on a lock that the panicking
+// thread may be holding, and give nothing back instead.
+
+/// The breadcrumbs, oldest first.
+pub fn snapshot() -> Vec<Breadcrumb> {
+    match BREADCRUMBS.try_lock() {
+        Ok(breadcrumbs) => breadcrumbs.iter().cloned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn open_window_count() -> Option<usize> {
+    OPEN_WINDOWS.try_lock().ok().map(|open_windows| open_windows.len())
+}
@@ -1,34 +1,25 @@
+// Crash report sink for Bugsnag's error reporting api, see crash_report.rs.
 // https://bugsnagerrorreportingapi.docs.apiary.io/#reference/0/minidump
 
 use serde::{Serialize, Deserialize};
-use backtrace::{self, Symbol};
-use std::path::Path;
-use serde_json::to_string_pretty;
-use std::collections::HashMap;
-use reqwest::header::{HeaderMap, CONTENT_TYPE};
-use tokio::runtime::Runtime;
-extern crate chrono;
-use chrono::prelude::*;
-use std::env;
+use std::collections::{BTreeMap, HashMap};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use serde_json::Value;
+use crate::breadcrumbs::Breadcrumb;
+use crate::crash_report::{CrashReport, CrashReportSink, JsonFrame};
+use crate::crash_spool::UploadTarget;
 
-#[derive(Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct JsonFrame {
-    file: String,
-    line_number: u32,
-    column_number: u32,
-    method: String,
-    in_project: bool,
-    code: HashMap<u32, String>,
-}
+static BUGSNAG_NOTIFY_URL: &'static str = "https://notify.bugsnag.com/";
+const BUGSNAG_PAYLOAD_VERSION: &str = "5";
+const NOTIFIER_NAME: &str = "Phoenix Code Desktop";
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct BugsnagEvent {
     exceptions: Vec<BugsnagException>,
-    breadcrumbs: Vec<()>,
+    breadcrumbs: Vec<Breadcrumb>,
     request: HashMap<String, String>,
-    threads: Vec<()>,
+    threads: Vec<BugsnagThread>,
     context: String,
     grouping_hash: String,
     unhandled: bool,
@@ -40,7 +31,7 @@ struct BugsnagEvent {
     device: BugsnagDevice,
     session: HashMap<String, String>,
     feature_flags: Vec<()>,
-    meta_data: HashMap<String, String>,
+    meta_data: BTreeMap<String, Value>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -53,6 +44,15 @@ struct BugsnagException {
     error_type: String,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BugsnagThread {
+    id: String,
+    name: String,
+    error_reporting_thread: bool,
+    stacktrace: Vec<JsonFrame>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct BugsnagSeverityReason {
@@ -99,202 +99,93 @@ struct BugsnagNotifier {
     dependencies: Vec<()>,
 }
 
-/// Redacts paths that are not recognized as well-known system paths.
-fn redact_path_for_privacy(path: &str) -> String {
-    let known_paths = [
-        // Linux common paths
-        "/usr", "/lib", "/var", "/etc", "/bin", "/sbin", "/dev", "/opt", "/boot", "/sys", "/proc",
-        "/run", "/tmp", "/root",
-
-        // macOS common paths
-        "/Applications", "/System", "/Library", "/private", "/opt", "/sbin",
-        "/usr/local",
-
-        // Windows common paths
-        "C:\\Windows", "C:\\Windows\\System32", "C:\\Program Files", "C:\\Program Files (x86)",
-        "C:\\ProgramData",
-    ];
-    let path_obj = Path::new(path);
-
-    if known_paths.iter().any(|&p| path.starts_with(p)) {
-        path.to_string()
-    } else {
-        format!("{} (path redacted for privacy)", path_obj.file_name().unwrap_or_default().to_str().unwrap_or("Unknown"))
-    }
+pub struct BugsnagSink {
+    endpoint: String,
+    api_key: String,
 }
 
-// The function `from_symbol` now returns a JSON string
-pub fn from_symbol(trace: &Symbol) -> JsonFrame
-{
-    let manifest_dir = env!("CARGO_MANIFEST_DIR");
-     let path = trace.filename()
-        .and_then(|p| p.to_str())  // Convert Path to &str as soon as possible
-        .unwrap_or("");  // Deal with None cases early, providing a default &str
-
-     let file = if path.starts_with(manifest_dir) {
-         Path::new(path)
-             .strip_prefix(manifest_dir)
-             .map(|p| p.to_str().unwrap_or("/pathRedacted/Unknown"))
-             .unwrap_or("/pathRedacted/Unknown")  // Use .unwrap_or here
-             .to_string()
-     } else {
-         redact_path_for_privacy(path)
-     };
-    let linenumber = trace.lineno().unwrap_or(0);
-    let columnnumber = trace.colno().unwrap_or(0);
-    let method = trace.name()
-        .map(|name| name.to_string())
-        .unwrap_or_else(|| "unknown".to_string());
-
-    // Example hardcoded code snippet, this part should be dynamically populated based on your context
-    let code_snippet = std::collections::HashMap::new();
-    // code_snippet.insert(1, "def a".to_string()); // add here is there are code snippets to show
-
-    JsonFrame {
-        file: file.to_string(),
-        line_number: linenumber,
-        column_number: columnnumber,
-        method: method.clone(),
-        in_project: path.starts_with(manifest_dir),
-        code: code_snippet,
+impl BugsnagSink {
+    /// `endpoint` defaults to Bugsnag's own notify url.
+    pub fn new(endpoint: Option<String>, api_key: String) -> Self {
+        BugsnagSink { endpoint: endpoint.unwrap_or_else(|| BUGSNAG_NOTIFY_URL.to_string()), api_key }
     }
-}
-
-async fn send_bugsnag_notification(notification: BugsnagNotification) -> Result<(), reqwest::Error> {
-    let client = reqwest::Client::new();
-    let headers = construct_headers();
 
-    let response = client.post("https://notify.bugsnag.com/")
-        .headers(headers)
-        .json(&notification)
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        println!("Status: {}", response.status());
-        if let Ok(resp_body) = response.text().await {
-            println!("Response: {}", resp_body);
+    fn construct_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        // an api key that isn't a valid header value is sent without it, and rejected by the server
+        if let Ok(api_key) = HeaderValue::from_str(&self.api_key) {
+            headers.insert("Bugsnag-Api-Key", api_key);
         }
-    } else {
-        eprintln!("Failed to send notification: {}", response.status());
+        headers.insert("Bugsnag-Payload-Version", HeaderValue::from_static(BUGSNAG_PAYLOAD_VERSION));
+        headers
     }
-
-    Ok(())
 }
 
-fn construct_headers() -> HeaderMap {
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
-    headers.insert("Bugsnag-Api-Key", "4dc115247125339aa551f3b11a5bee6b".parse().unwrap());
-    headers.insert("Bugsnag-Payload-Version", "5".parse().unwrap());
-    headers
-}
-
-fn get_current_time() -> String {
-    // Get the current UTC time
-    let now = Utc::now();
-
-    // Format the time to a string in ISO 8601 format with milliseconds
-    now.to_rfc3339_opts(SecondsFormat::Millis, true)
-}
-
-fn get_sysinfo() -> (String, String, String, String) {
-    let info = os_info::get();
-    let os_type = info.os_type().to_string();
-    let os_release = info.version().to_string();
-
-    let arch = info.architecture().unwrap_or("unknown").to_string();
-    let exe_path = env::args().next().unwrap_or_else(|| "Unknown Executable".to_string());
-    let exe_name = Path::new(&exe_path)
-        .file_name()
-        .and_then(|name| name.to_str())
-        .unwrap_or("Unknown Executable")
-        .to_string();
-
-    (os_type, os_release, arch, exe_name)
-}
-
-pub fn handle(message: &String){
-    let mut result: Vec<JsonFrame> = Vec::new();
-    backtrace::trace(|frame| {
-        backtrace::resolve(frame.ip(), |symbol| {
-            result.push(from_symbol(&symbol))
-        });
-        true
-    });
-    let (os_type, os_version, arch, exe_name) = get_sysinfo();
-    let app_version = env!("CARGO_PKG_VERSION");
-    let current_time = get_current_time();
-     let event = BugsnagEvent {
-        exceptions: vec![
-            BugsnagException {
-                error_class: "Rust_Panic".to_string(),
-                message: message.to_string(),
-                stacktrace: result,
-                error_type: "rust".to_string(),
-            }
-        ],
-        breadcrumbs: vec![],
-        request: HashMap::new(),
-        threads: vec![],
-        context: message.to_string(),
-        grouping_hash: "bugsnag.rs".to_string(),
-        unhandled: true,
-        severity: "error".to_string(),
-        severity_reason: BugsnagSeverityReason {
-            reason_type: "unhandledError".to_string(),
-            attributes: HashMap::new(),
-            unhandled_overridden: false,
-        },
-        project_packages: vec![],
-        user: HashMap::new(),
-        app: BugsnagApp {
-            id: "phcode.io".to_string(),
-            version: app_version.to_string(),
-            release_stage: exe_name.to_string(),
-            binary_arch: arch.to_string(),
-            running_on_rosetta: false,
-        },
-        device: BugsnagDevice {
-            os_name: os_type.to_string(),
-            os_version: os_version.to_string(),
-            time: current_time.to_string(),
-            cpu_abi: vec![arch.to_string()],
-            runtime_versions: HashMap::new(),
-        },
-        session: HashMap::new(),
-        feature_flags: vec![],
-        meta_data: HashMap::new(),
-    };
-
-    let notification = BugsnagNotification {
-        api_key: "4dc115247125339aa551f3b11a5bee6b".to_string(),
-        payload_version: "5".to_string(),
-        notifier: BugsnagNotifier {
-            name: "Bugsnag Ruby".to_string(),
-            version: "1.0.11".to_string(),
-            url: "https://github.com/bugsnag/bugsnag-ruby".to_string(),
-            dependencies: vec![],
-        },
-        events: vec![event],
-    };
+impl CrashReportSink for BugsnagSink {
+    fn upload_target(&self) -> Option<UploadTarget> {
+        Some(UploadTarget { url: self.endpoint.clone(), headers: self.construct_headers() })
+    }
 
-    // Serialize and print the notification object
-    if let Ok(json_string) = to_string_pretty(&notification) {
-        println!("Complete Bugsnag error report JSON:\n{}", json_string);
-    } else {
-        println!("Failed to serialize Bugsnag notification.");
+    fn encode(&self, report: &CrashReport) -> Result<Vec<u8>, String> {
+        let event = BugsnagEvent {
+            exceptions: vec![
+                BugsnagException {
+                    error_class: report.error_class.clone(),
+                    message: report.message.clone(),
+                    stacktrace: report.stacktrace.clone(),
+                    error_type: report.error_type.clone(),
+                }
+            ],
+            breadcrumbs: report.breadcrumbs.clone(),
+            request: HashMap::new(),
+            threads: report.threads.iter().map(|thread| BugsnagThread {
+                id: thread.id.clone(),
+                name: thread.name.clone(),
+                error_reporting_thread: thread.error_reporting_thread,
+                stacktrace: thread.stacktrace.clone(),
+            }).collect(),
+            context: report.context.clone(),
+            grouping_hash: report.grouping_hash.clone(),
+            unhandled: report.unhandled,
+            severity: report.severity.clone(),
+            severity_reason: BugsnagSeverityReason {
+                reason_type: if report.unhandled { "unhandledError" } else { "handledError" }.to_string(),
+                attributes: HashMap::new(),
+                unhandled_overridden: false,
+            },
+            project_packages: vec![],
+            user: HashMap::new(),
+            app: BugsnagApp {
+                id: "phcode.io".to_string(),
+                version: report.app_version.clone(),
+                release_stage: report.release_stage.clone(),
+                binary_arch: report.arch.clone(),
+                running_on_rosetta: false,
+            },
+            device: BugsnagDevice {
+                os_name: report.os_name.clone(),
+                os_version: report.os_version.clone(),
+                time: report.time.clone(),
+                cpu_abi: vec![report.arch.clone()],
+                runtime_versions: HashMap::new(),
+            },
+            session: HashMap::new(),
+            feature_flags: vec![],
+            meta_data: report.meta_data.clone(),
+        };
+
+        let notification = BugsnagNotification {
+            api_key: self.api_key.clone(),
+            payload_version: BUGSNAG_PAYLOAD_VERSION.to_string(),
+            notifier: BugsnagNotifier {
+                name: NOTIFIER_NAME.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                url: env!("CARGO_PKG_REPOSITORY").to_string(),
+                dependencies: vec![],
+            },
+            events: vec![event],
+        };
+        serde_json::to_vec(&notification).map_err(|e| e.to_string())
     }
-    // Spawning a new thread to handle the asynchronous task
-    let handle = std::thread::spawn(move || {
-       // Creating a new Tokio runtime
-       let rt = Runtime::new().unwrap();
-       rt.block_on(async {
-           if let Err(e) = send_bugsnag_notification(notification).await {
-               eprintln!("Failed to send notification to Bugsnag: {}", e);
-           }
-       });
-    });
-    // Wait for the thread to finish
-    handle.join().unwrap();
-}
\ No newline at end of file
+}
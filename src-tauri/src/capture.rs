@@ -0,0 +1,365 @@
+// Platform independent pixel helpers for `capture_page`. The platform code grabs the webview
+// pixels, these crop, scale and encode them and deliver the result where the caller asked.
+
+use base64::Engine;
+use image::ImageEncoder;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use crate::utilities::phtauri_origin;
+
+/// Encoded captures above this size are handed out as a protocol url instead of inline data, as
+/// IPC payloads of that size are slow to serialize.
+const MAX_INLINE_CAPTURE_BYTES: usize = 2 * 1024 * 1024;
+/// Captures waiting to be fetched through the protocol url. Older ones are dropped first.
+const MAX_CACHED_CAPTURES: usize = 8;
+// served by the phtauri protocol handler, see `cached_capture`
+const CAPTURE_PATH_PREFIX: &str = "/__capture__/";
+const DEFAULT_JPEG_QUALITY: u8 = 90;
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureFormat {
+    #[default]
+    Png,
+    Jpeg,
+    /// Always lossless, `quality` is ignored.
+    Webp,
+}
+
+impl CaptureFormat {
+    fn mime_type(&self) -> &'static str {
+        match self {
+            CaptureFormat::Png => "image/png",
+            CaptureFormat::Jpeg => "image/jpeg",
+            CaptureFormat::Webp => "image/webp",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            CaptureFormat::Png => "png",
+            CaptureFormat::Jpeg => "jpg",
+            CaptureFormat::Webp => "webp",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureDestination {
+    /// Inline base64 data, or a url if the image is too large to send inline.
+    #[default]
+    Bytes,
+    /// Written to `fileName` in the capture directory, or to a generated name if none is given.
+    File,
+    /// Put on the system clipboard as an image.
+    Clipboard,
+    /// A one time `phtauri://` url the image can be fetched from.
+    Url,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CaptureOptions {
+    pub format: CaptureFormat,
+    /// JPEG quality from 1 to 100.
+    pub quality: Option<u8>,
+    /// Scales the image down so that neither side exceeds this many pixels.
+    pub max_dimension: Option<u32>,
+    /// Scales the image by this factor, applied before `max_dimension`.
+    pub scale: Option<f64>,
+    pub destination: CaptureDestination,
+    /// Name of the file for the `file` destination. Only a plain file name is accepted, the file
+    /// always goes into the capture directory so that a page can't overwrite arbitrary files.
+    pub file_name: Option<String>,
+    /// Scrolls through the page and captures all of it instead of the visible viewport. The
+    /// capture rect is ignored.
+    pub full_page: bool,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureResult {
+    pub width: u32,
+    pub height: u32,
+    pub mime_type: String,
+    /// base64 encoded image, set for the `bytes` destination.
+    pub data: Option<String>,
+    pub file_path: Option<String>,
+    pub url: Option<String>,
+}
+
+/// A capture region in physical pixels, clamped to the captured image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Clamps a region given in physical pixels to an image of `full_width` x `full_height`.
+/// Returns the whole image when `region` is None.
+pub fn clamp_region(region: Option<(f64, f64, f64, f64)>, full_width: u32, full_height: u32) -> Result<PixelRegion, String> {
+    let (x, y, width, height) = match region {
+        Some((x, y, width, height)) => {
+            let x = (x as i64).max(0).min(full_width as i64);
+            let y = (y as i64).max(0).min(full_height as i64);
+            let width = (width as i64).min(full_width as i64 - x).max(0);
+            let height = (height as i64).min(full_height as i64 - y).max(0);
+            (x, y, width, height)
+        }
+        None => (0, 0, full_width as i64, full_height as i64),
+    };
+    if width <= 0 || height <= 0 {
+        return Err("Capture region is empty".to_string());
+    }
+    Ok(PixelRegion { x: x as u32, y: y as u32, width: width as u32, height: height as u32 })
+}
+
+/// Copies `region` out of a tightly packed RGBA buffer of `full_width` pixels per row.
+pub fn crop_rgba(pixels: &[u8], full_width: u32, region: &PixelRegion) -> Vec<u8> {
+    let mut region_pixels = Vec::with_capacity((region.width * region.height * 4) as usize);
+    for y in region.y..(region.y + region.height) {
+        let start = ((y * full_width + region.x) * 4) as usize;
+        let end = start + (region.width * 4) as usize;
+        region_pixels.extend_from_slice(&pixels[start..end]);
+    }
+    region_pixels
+}
+
+/// Converts cairo's native-endian premultiplied ARGB32 rows (BGRA in memory on little endian)
+/// to a tightly packed, straight alpha RGBA buffer.
+#[cfg(target_os = "linux")]
+pub fn premultiplied_bgra_to_rgba(data: &[u8], width: u32, height: u32, stride: usize) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+    for row in data.chunks(stride).take(height as usize) {
+        for pixel in row[..(width * 4) as usize].chunks_exact(4) {
+            let argb = u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]);
+            let a = (argb >> 24) & 0xff;
+            let unpremultiply = |c: u32| -> u8 {
+                if a == 0 { 0 } else { ((c * 255 + a / 2) / a).min(255) as u8 }
+            };
+            pixels.push(unpremultiply((argb >> 16) & 0xff));
+            pixels.push(unpremultiply((argb >> 8) & 0xff));
+            pixels.push(unpremultiply(argb & 0xff));
+            pixels.push(a as u8);
+        }
+    }
+    pixels
+}
+
+pub fn encode_png(rgba: &[u8], width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let mut png_bytes: Vec<u8> = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_bytes, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer.write_image_data(rgba).map_err(|e| e.to_string())?;
+    }
+    Ok(png_bytes)
+}
+
+/// Tallest full page capture we stitch, in physical pixels. Pages longer than this are cut off.
+pub const MAX_FULL_PAGE_HEIGHT: u32 = 32768;
+
+/// One viewport capture of a full page capture as tightly packed RGBA, placed `offset_y`
+/// physical pixels from the top of the page.
+pub struct PageTile {
+    pub rgba: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub offset_y: u32,
+}
+
+/// Stitches viewport captures into one PNG of `page_height` physical pixels. Tiles may overlap,
+/// the later tile wins. All tiles must have the width of the first one.
+pub fn stitch_tiles(tiles: &[PageTile], page_height: u32) -> Result<Vec<u8>, String> {
+    let width = tiles.first().ok_or("Nothing to stitch")?.width;
+    let page_height = page_height.clamp(1, MAX_FULL_PAGE_HEIGHT);
+    let row_bytes = (width * 4) as usize;
+    let mut page = vec![0u8; row_bytes * page_height as usize];
+    for tile in tiles {
+        if tile.width != width {
+            return Err("Page was resized while capturing".to_string());
+        }
+        let rows = tile.height.min(page_height.saturating_sub(tile.offset_y)) as usize;
+        if rows == 0 {
+            continue;
+        }
+        let start = tile.offset_y as usize * row_bytes;
+        page[start..start + rows * row_bytes].copy_from_slice(&tile.rgba[..rows * row_bytes]);
+    }
+    encode_png(&page, width, page_height)
+}
+
+/// Decodes a PNG into a tightly packed RGBA buffer.
+pub fn decode_png(png_bytes: &[u8]) -> Result<(Vec<u8>, u32, u32), String> {
+    let image = image::load_from_memory_with_format(png_bytes, image::ImageFormat::Png)
+        .map_err(|e| e.to_string())?
+        .into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok((image.into_raw(), width, height))
+}
+
+/// Output size after applying `scale` and then `max_dimension`. Images are never scaled up
+/// by `max_dimension` and never below 1x1.
+pub fn scaled_size(width: u32, height: u32, scale: Option<f64>, max_dimension: Option<u32>) -> (u32, u32) {
+    let mut factor = match scale {
+        Some(scale) if scale > 0.0 => scale,
+        _ => 1.0,
+    };
+    if let Some(max_dimension) = max_dimension {
+        let largest_side = (width.max(height) as f64 * factor).max(1.0);
+        if max_dimension > 0 && largest_side > max_dimension as f64 {
+            factor *= max_dimension as f64 / largest_side;
+        }
+    }
+    let scaled = |side: u32| ((side as f64 * factor).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+pub fn encode_image(rgba: &[u8], width: u32, height: u32, format: CaptureFormat, quality: Option<u8>) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = Vec::new();
+    match format {
+        CaptureFormat::Png => return encode_png(rgba, width, height),
+        CaptureFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb: Vec<u8> = rgba.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect();
+            let quality = quality.unwrap_or(DEFAULT_JPEG_QUALITY).clamp(1, 100);
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, quality)
+                .write_image(&rgb, width, height, image::ExtendedColorType::Rgb8)
+                .map_err(|e| e.to_string())?;
+        }
+        CaptureFormat::Webp => {
+            image::codecs::webp::WebPEncoder::new_lossless(&mut bytes)
+                .write_image(rgba, width, height, image::ExtendedColorType::Rgba8)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(bytes)
+}
+
+static CACHED_CAPTURES: Lazy<Mutex<VecDeque<(String, &'static str, Vec<u8>)>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// Random, as the name is also the one time url of a cached capture, which other pages that can
+// load phtauri resources shouldn't be able to guess.
+fn next_capture_name(format: CaptureFormat) -> String {
+    let mut token = [0u8; 16];
+    OsRng.fill_bytes(&mut token);
+    format!("capture-{}.{}", hex::encode(token), format.extension())
+}
+
+fn cache_capture(bytes: Vec<u8>, format: CaptureFormat) -> String {
+    let name = next_capture_name(format);
+    let mut cached_captures = CACHED_CAPTURES.lock().unwrap();
+    while cached_captures.len() >= MAX_CACHED_CAPTURES {
+        cached_captures.pop_front();
+    }
+    cached_captures.push_back((name.clone(), format.mime_type(), bytes));
+    format!("{}{}{}", phtauri_origin(), CAPTURE_PATH_PREFIX, name)
+}
+
+/// Returns the mime type and bytes of a capture handed out with a url. Each url can be
+/// fetched once.
+pub fn cached_capture(path: &str) -> Option<(&'static str, Vec<u8>)> {
+    let name = path.strip_prefix(CAPTURE_PATH_PREFIX)?;
+    let mut cached_captures = CACHED_CAPTURES.lock().unwrap();
+    let index = cached_captures.iter().position(|(cached_name, _, _)| cached_name == name)?;
+    cached_captures.remove(index).map(|(_, mime_type, bytes)| (mime_type, bytes))
+}
+
+/// Directory the `file` destination writes to.
+pub fn capture_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join("phcode-captures")
+}
+
+// A file name without any directory part, so that joining it to the capture dir stays inside it.
+fn is_plain_file_name(file_name: &str) -> bool {
+    !file_name.is_empty() && file_name != "." && file_name != ".."
+        && !file_name.contains(['/', '\\', ':', '\0'])
+}
+
+fn write_capture_file(bytes: &[u8], file_name: Option<&str>, format: CaptureFormat) -> Result<String, String> {
+    let file_name = match file_name {
+        Some(file_name) if is_plain_file_name(file_name) => file_name.to_string(),
+        Some(file_name) => return Err(format!("Invalid capture file name: {}", file_name)),
+        None => next_capture_name(format),
+    };
+    let dir = capture_dir();
+    std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    let file_path = dir.join(file_name);
+    std::fs::write(&file_path, bytes).map_err(|e| e.to_string())?;
+    Ok(file_path.to_string_lossy().to_string())
+}
+
+// Kept for the life of the app. On X11 and Wayland the process that set the clipboard serves its
+// contents, so they are gone once the `Clipboard` is dropped unless a clipboard manager took
+// them over.
+static CLIPBOARD: Lazy<Mutex<Option<arboard::Clipboard>>> = Lazy::new(|| Mutex::new(None));
+
+fn copy_image_to_clipboard(rgba: Vec<u8>, width: u32, height: u32) -> Result<(), String> {
+    let mut clipboard = CLIPBOARD.lock().unwrap_or_else(|e| e.into_inner());
+    if clipboard.is_none() {
+        *clipboard = Some(arboard::Clipboard::new().map_err(|e| e.to_string())?);
+    }
+    let clipboard = clipboard.as_mut().ok_or("Clipboard unavailable")?;
+    clipboard.set_image(arboard::ImageData {
+        width: width as usize,
+        height: height as usize,
+        bytes: std::borrow::Cow::Owned(rgba),
+    }).map_err(|e| e.to_string())
+}
+
+/// Scales and re-encodes a PNG capture as requested by `options` and delivers it to the
+/// requested destination.
+pub fn process_capture(png_bytes: Vec<u8>, options: &CaptureOptions) -> Result<CaptureResult, String> {
+    let (mut rgba, mut width, mut height) = decode_png(&png_bytes)?;
+    let (scaled_width, scaled_height) = scaled_size(width, height, options.scale, options.max_dimension);
+    let resized = (scaled_width, scaled_height) != (width, height);
+    if resized {
+        let image = image::RgbaImage::from_raw(width, height, rgba)
+            .ok_or("Capture has an unexpected size")?;
+        rgba = image::imageops::resize(&image, scaled_width, scaled_height, image::imageops::FilterType::Triangle)
+            .into_raw();
+        width = scaled_width;
+        height = scaled_height;
+    }
+
+    let mut result = CaptureResult {
+        width,
+        height,
+        mime_type: options.format.mime_type().to_string(),
+        ..Default::default()
+    };
+    let encode = || if options.format == CaptureFormat::Png && !resized {
+        Ok(png_bytes)
+    } else {
+        encode_image(&rgba, width, height, options.format, options.quality)
+    };
+    match options.destination {
+        CaptureDestination::Bytes => {
+            let encoded = encode()?;
+            if encoded.len() <= MAX_INLINE_CAPTURE_BYTES {
+                result.data = Some(base64::engine::general_purpose::STANDARD.encode(&encoded));
+            } else {
+                result.url = Some(cache_capture(encoded, options.format));
+            }
+        }
+        CaptureDestination::Url => {
+            result.url = Some(cache_capture(encode()?, options.format));
+        }
+        CaptureDestination::File => {
+            result.file_path = Some(write_capture_file(&encode()?, options.file_name.as_deref(), options.format)?);
+        }
+        CaptureDestination::Clipboard => {
+            copy_image_to_clipboard(rgba, width, height)?;
+        }
+    }
+    Ok(result)
+}
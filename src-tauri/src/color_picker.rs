@@ -0,0 +1,100 @@
+// Screen color picker for platforms without a native color sampler (Linux and Windows).
+// The calling window is captured with the `capture_page` machinery and covered by an
+// undecorated overlay window showing that still capture, with a magnifier loupe. The overlay
+// reports the pixel the user clicked as "#rrggbb", or "" when dismissed with a right click or by
+// closing it. Escape and a capture that fails to load navigate the overlay to its cancel page
+// instead, which closes it natively even if the overlay can't reach the IPC.
+
+use once_cell::sync::Lazy;
+use std::sync::{Arc, Mutex};
+use tauri::Manager;
+use crate::utilities::phtauri_origin;
+
+pub const OVERLAY_WINDOW_LABEL: &str = "colorPickerOverlay";
+// served by the phtauri protocol handler, see `overlay_asset`
+const OVERLAY_PATH_PREFIX: &str = "/__color_picker__/";
+const OVERLAY_HTML: &str = include_str!("color_picker.html");
+// the overlay navigates here to cancel the pick, the navigation itself is never allowed
+const OVERLAY_CANCEL_PATH: &str = "/__color_picker__/cancel";
+
+struct PendingPick {
+    capture_png: Arc<Vec<u8>>,
+    result_tx: tokio::sync::oneshot::Sender<String>,
+}
+
+// Only one pick can be in progress at a time, as there is only one mouse.
+static PENDING_PICK: Lazy<Mutex<Option<PendingPick>>> = Lazy::new(|| Mutex::new(None));
+
+fn overlay_url() -> String {
+    format!("{}{}index.html", phtauri_origin(), OVERLAY_PATH_PREFIX)
+}
+
+/// Returns the mime type and bytes of an overlay asset if `path` points into the overlay.
+pub fn overlay_asset(path: &str) -> Option<(&'static str, Vec<u8>)> {
+    let file = path.strip_prefix(OVERLAY_PATH_PREFIX)?;
+    match file {
+        "index.html" => Some(("text/html", OVERLAY_HTML.as_bytes().to_vec())),
+        "capture.png" => {
+            let pending = PENDING_PICK.lock().unwrap();
+            pending.as_ref().map(|pending| ("image/png", pending.capture_png.as_ref().clone()))
+        }
+        _ => None,
+    }
+}
+
+/// Resolves the pending pick with `color` and closes the overlay.
+pub fn finish_pick(app: &tauri::AppHandle, color: String) {
+    // closing the overlay fires CloseRequested, which calls back here with nothing pending
+    let Some(pending) = PENDING_PICK.lock().unwrap().take() else {
+        return;
+    };
+    let _ = pending.result_tx.send(color);
+    if let Some(overlay) = app.get_window(OVERLAY_WINDOW_LABEL) {
+        let _ = overlay.close();
+    }
+}
+
+/// Shows the overlay over `window` with `capture_png`, a capture of its client area, and waits
+/// for the user to pick a color.
+pub async fn pick_color(window: tauri::Window, capture_png: Vec<u8>) -> Result<String, String> {
+    let (tx, rx) = tokio::sync::oneshot::channel::<String>();
+    {
+        let mut pending = PENDING_PICK.lock().unwrap();
+        if pending.is_some() {
+            return Err("A color pick is already in progress".to_string());
+        }
+        *pending = Some(PendingPick { capture_png: Arc::new(capture_png), result_tx: tx });
+    }
+
+    let overlay = (|| -> Result<tauri::Window, String> {
+        let scale_factor = window.scale_factor().map_err(|e| e.to_string())?;
+        let position = window.inner_position().map_err(|e| e.to_string())?.to_logical::<f64>(scale_factor);
+        let size = window.inner_size().map_err(|e| e.to_string())?.to_logical::<f64>(scale_factor);
+        let url = tauri::Url::parse(&overlay_url()).map_err(|e| e.to_string())?;
+        let app = window.app_handle();
+        tauri::WindowBuilder::new(&window, OVERLAY_WINDOW_LABEL, tauri::WindowUrl::External(url))
+            .on_navigation(move |url| {
+                if url.path() == OVERLAY_CANCEL_PATH {
+                    finish_pick(&app, String::new());
+                    return false;
+                }
+                true
+            })
+            .title("Pick a color")
+            .decorations(false)
+            .resizable(false)
+            .always_on_top(true)
+            .skip_taskbar(true)
+            .focused(true)
+            .position(position.x, position.y)
+            .inner_size(size.width, size.height)
+            .build()
+            .map_err(|e| e.to_string())
+    })();
+    if let Err(e) = overlay {
+        PENDING_PICK.lock().unwrap().take();
+        return Err(format!("Failed to open color picker: {}", e));
+    }
+
+    rx.await.map_err(|_| "Color picker channel closed".to_string())
+}
@@ -0,0 +1,271 @@
+// Cleanup of the stack frames of crash reports, and the grouping hash that decides which reports
+// are the same issue. Frames are innermost first, as captured by `backtrace::trace`.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use crate::crash_report::JsonFrame;
+
+/// Number of in-project frames below the panic that go into the grouping hash.
+const GROUPING_FRAMES: usize = 3;
+
+// "::h" + 16 hex digits at the end of legacy mangled symbols
+static LEGACY_HASH_SUFFIX: Lazy<Regex> = Lazy::new(|| Regex::new(r"::h[0-9a-f]{16}$").unwrap());
+// crate disambiguators of v0 mangled symbols, like "core[5e2a1f3b9c0d7e4a]::panicking". At least
+// 8 digits so that slices like "[f32]" are left alone.
+static V0_CRATE_HASH: Lazy<Regex> = Lazy::new(|| Regex::new(r"\[[0-9a-f]{8,}\]").unwrap());
+
+// Frames of the code that captures the backtrace, above the panic machinery.
+static CAPTURE_FRAME_PREFIXES: &[&str] = &[
+    "backtrace::",
+    concat!(env!("CARGO_CRATE_NAME"), "::crash_report::"),
+];
+
+// Frames of std running the panic, between the panic hook and the code that panicked.
+static PANIC_FRAME_PREFIXES: &[&str] = &[
+    "std::panicking::",
+    "core::panicking::",
+    "std::panic::",
+    "core::panic::",
+    "std::sys_common::backtrace::__rust_end_short_backtrace",
+    "std::sys::backtrace::__rust_end_short_backtrace",
+    "rust_begin_unwind",
+    "rust_panic",
+    "__rust_start_panic",
+    // std symbols of newer toolchains, like "__rustc::rust_begin_unwind"
+    "__rustc::",
+    "core::result::unwrap_failed",
+    "core::option::unwrap_failed",
+    "core::option::expect_failed",
+];
+// `Result::unwrap` and friends, "core::result::Result<T,E>::unwrap" in legacy mangling and
+// "<core::result::Result<i32, ...>>::unwrap" in v0
+static UNWRAP_FRAME: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^<?core::(result::Result|option::Option)<.*>>?::(unwrap|expect)(_err)?$").unwrap()
+});
+
+/// Strips the hashes rustc adds to symbol names, so that the same function has the same name
+/// in every build.
+pub fn normalize_symbol(name: &str) -> String {
+    let name = LEGACY_HASH_SUFFIX.replace(name, "");
+    V0_CRATE_HASH.replace_all(&name, "").to_string()
+}
+
+fn has_prefix(method: &str, prefixes: &[&str]) -> bool {
+    prefixes.iter().any(|prefix| method.starts_with(prefix))
+}
+
+/// Drops the frames of the backtrace capture, the panic hook and the panic machinery, so that
+/// the stack starts where the panic was raised. A stack without panic frames only loses the
+/// capture frames at its top.
+pub fn trim_panic_frames(frames: Vec<JsonFrame>) -> Vec<JsonFrame> {
+    // the end of the first run of panic frames. Unresolved frames neither start nor end the run.
+    let mut panic_end = None;
+    for (index, frame) in frames.iter().enumerate() {
+        if has_prefix(&frame.method, PANIC_FRAME_PREFIXES) || UNWRAP_FRAME.is_match(&frame.method) {
+            panic_end = Some(index + 1);
+        } else if panic_end.is_some() && frame.method != "unknown" {
+            break;
+        }
+    }
+    let start = panic_end.unwrap_or_else(|| {
+        frames.iter().take_while(|frame| has_prefix(&frame.method, CAPTURE_FRAME_PREFIXES)).count()
+    });
+    frames.into_iter().skip(start).collect()
+}
+
+/// Hash of the panic location, "file:line", and the top in-project frames of the trimmed
+/// stack. Line numbers of the frames are left out so that unrelated edits don't split issues.
+pub fn grouping_hash(location: Option<&str>, frames: &[JsonFrame]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(location.unwrap_or("unknown location").as_bytes());
+    for frame in frames.iter().filter(|frame| frame.in_project).take(GROUPING_FRAMES) {
+        hasher.update(b"\n");
+        hasher.update(frame.file.as_bytes());
+        hasher.update(b":");
+        hasher.update(frame.method.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Symbol names as `backtrace` resolved them for real panics, before normalization.
+
+    // `panic!("literal")` on an older toolchain, legacy mangling
+    const LEGACY_PANIC_LITERAL: &[&str] = &[
+        "backtrace::backtrace::libunwind::trace::h2a4b3f5e1c7d9e08",
+        "backtrace::backtrace::trace_unsynchronized::h6d0c9a1b2e3f4a57",
+        "phoenix_code_ide::crash_report::handle::{{closure}}::h0f1e2d3c4b5a6978",
+        "phoenix_code_ide::crash_report::handle::h1e2d3c4b5a697887",
+        "phoenix_code_ide::main::{{closure}}::h8a7b6c5d4e3f2a10",
+        "std::panicking::rust_panic_with_hook::h1b2c3d4e5f6a7b8c",
+        "std::panicking::begin_panic::{{closure}}::h9c8b7a6d5e4f3a2b",
+        "std::sys_common::backtrace::__rust_end_short_backtrace::h3e4f5a6b7c8d9e0f",
+        "std::panicking::begin_panic::h4f5a6b7c8d9e0f1a",
+        "phoenix_code_ide::storage::Storage::put::h5a6b7c8d9e0f1a2b",
+        "phoenix_code_ide::main::{{closure}}::h6b7c8d9e0f1a2b3c",
+        "std::rt::lang_start_internal::h7c8d9e0f1a2b3c4d",
+    ];
+
+    // `result.unwrap()` on an `Err`, legacy mangling
+    const LEGACY_UNWRAP: &[&str] = &[
+        "backtrace::backtrace::libunwind::trace::h2a4b3f5e1c7d9e08",
+        "backtrace::backtrace::trace_unsynchronized::h6d0c9a1b2e3f4a57",
+        "phoenix_code_ide::crash_report::handle::{{closure}}::h0f1e2d3c4b5a6978",
+        "phoenix_code_ide::crash_report::handle::h1e2d3c4b5a697887",
+        "phoenix_code_ide::main::{{closure}}::h8a7b6c5d4e3f2a10",
+        "std::panicking::rust_panic_with_hook::h1b2c3d4e5f6a7b8c",
+        "std::panicking::begin_panic_handler::{{closure}}::h2c3d4e5f6a7b8c9d",
+        "std::sys_common::backtrace::__rust_end_short_backtrace::h3e4f5a6b7c8d9e0f",
+        "rust_begin_unwind",
+        "core::panicking::panic_fmt::h0a1b2c3d4e5f6a7b",
+        "core::result::unwrap_failed::h1a2b3c4d5e6f7a8b",
+        "core::result::Result<T,E>::unwrap::h2b3c4d5e6f7a8b9c",
+        "phoenix_code_ide::boot_config::read_boot_config::h3c4d5e6f7a8b9c0d",
+        "phoenix_code_ide::main::h4d5e6f7a8b9c0d1e",
+    ];
+
+    // `panic!("bad {}", x)` on a newer toolchain, v0 mangling
+    const V0_PANIC_FORMATTED: &[&str] = &[
+        "backtrace[7a1c3e5f9b2d4a6c]::backtrace::libunwind::trace",
+        "backtrace[7a1c3e5f9b2d4a6c]::backtrace::trace_unsynchronized::<phoenix_code_ide[1f2e3d4c5b6a7988]::crash_report::handle::{closure#0}>",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::crash_report::handle::{closure#0}",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::crash_report::handle",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::main::{closure#0}",
+        "std[8e1d8c5a1c2b3f4e]::panicking::rust_panic_with_hook",
+        "std[8e1d8c5a1c2b3f4e]::panicking::begin_panic_handler::{closure#0}",
+        "std[8e1d8c5a1c2b3f4e]::sys::backtrace::__rust_end_short_backtrace::<std[8e1d8c5a1c2b3f4e]::panicking::begin_panic_handler::{closure#0}, !>",
+        "__rustc::rust_begin_unwind",
+        "core[5e2a1f3b9c0d7e4a]::panicking::panic_fmt",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::capture::clamp_region",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::init::restore_window_state",
+    ];
+
+    // `option.expect("...")` on `None`, v0 mangling, with frames the symbolizer couldn't resolve
+    const V0_EXPECT_WITH_UNKNOWN: &[&str] = &[
+        "backtrace[7a1c3e5f9b2d4a6c]::backtrace::libunwind::trace",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::crash_report::handle",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::main::{closure#0}",
+        "std[8e1d8c5a1c2b3f4e]::panicking::rust_panic_with_hook",
+        "unknown",
+        "__rustc::rust_begin_unwind",
+        "core[5e2a1f3b9c0d7e4a]::panicking::panic_fmt",
+        "unknown",
+        "core[5e2a1f3b9c0d7e4a]::option::expect_failed",
+        "<core[5e2a1f3b9c0d7e4a]::option::Option<&str>>::expect",
+        "phoenix_code_ide[1f2e3d4c5b6a7988]::capture::capture_dir",
+        "unknown",
+    ];
+
+    fn frames(symbols: &[&str]) -> Vec<JsonFrame> {
+        symbols.iter().enumerate().map(|(index, symbol)| {
+            let method = if *symbol == "unknown" { symbol.to_string() } else { normalize_symbol(symbol) };
+            let in_project = method.starts_with("phoenix_code_ide::");
+            JsonFrame {
+                file: if in_project { "src/main.rs".to_string() } else { String::new() },
+                line_number: index as u32 + 1,
+                column_number: 5,
+                method,
+                in_project,
+                code: HashMap::new(),
+            }
+        }).collect()
+    }
+
+    fn methods(frames: &[JsonFrame]) -> Vec<&str> {
+        frames.iter().map(|frame| frame.method.as_str()).collect()
+    }
+
+    #[test]
+    fn strips_legacy_and_v0_hashes() {
+        assert_eq!(normalize_symbol("phoenix_code_ide::storage::Storage::put::h5a6b7c8d9e0f1a2b"),
+                   "phoenix_code_ide::storage::Storage::put");
+        assert_eq!(normalize_symbol("<core[5e2a1f3b9c0d7e4a]::option::Option<&str>>::expect"),
+                   "<core::option::Option<&str>>::expect");
+        assert_eq!(normalize_symbol(V0_PANIC_FORMATTED[7]),
+                   "std::sys::backtrace::__rust_end_short_backtrace::<std::panicking::begin_panic_handler::{closure#0}, !>");
+    }
+
+    #[test]
+    fn slices_are_not_stripped() {
+        assert_eq!(normalize_symbol("<[f32] as core[5e2a1f3b9c0d7e4a]::slice::sort::stable::BufGuard<f32>>::with_capacity"),
+                   "<[f32] as core::slice::sort::stable::BufGuard<f32>>::with_capacity");
+        assert_eq!(normalize_symbol("<alloc::vec::Vec<[u8; 16]>>::push::h2b3c4d5e6f7a8b9c"),
+                   "<alloc::vec::Vec<[u8; 16]>>::push");
+    }
+
+    #[test]
+    fn panic_with_literal() {
+        let trimmed = trim_panic_frames(frames(LEGACY_PANIC_LITERAL));
+        assert_eq!(methods(&trimmed), [
+            "phoenix_code_ide::storage::Storage::put",
+            "phoenix_code_ide::main::{{closure}}",
+            "std::rt::lang_start_internal",
+        ]);
+    }
+
+    #[test]
+    fn panic_with_formatted_message() {
+        let trimmed = trim_panic_frames(frames(V0_PANIC_FORMATTED));
+        assert_eq!(methods(&trimmed), [
+            "phoenix_code_ide::capture::clamp_region",
+            "phoenix_code_ide::init::restore_window_state",
+        ]);
+    }
+
+    #[test]
+    fn unwrap_panic_starts_at_the_caller() {
+        let trimmed = trim_panic_frames(frames(LEGACY_UNWRAP));
+        assert_eq!(methods(&trimmed), [
+            "phoenix_code_ide::boot_config::read_boot_config",
+            "phoenix_code_ide::main",
+        ]);
+    }
+
+    #[test]
+    fn unknown_frames_inside_the_panic_run_are_dropped() {
+        let trimmed = trim_panic_frames(frames(V0_EXPECT_WITH_UNKNOWN));
+        assert_eq!(methods(&trimmed), [
+            "phoenix_code_ide::capture::capture_dir",
+            "unknown",
+        ]);
+    }
+
+    #[test]
+    fn stack_without_panic_frames_only_loses_the_capture_frames() {
+        let symbols = [
+            "backtrace::backtrace::libunwind::trace::h2a4b3f5e1c7d9e08",
+            "phoenix_code_ide::crash_report::handle::{{closure}}::h0f1e2d3c4b5a6978",
+            "phoenix_code_ide::crash_report::handle::h1e2d3c4b5a697887",
+            "phoenix_code_ide::init::restore_window_state::h2d3c4b5a69788776",
+            "unknown",
+        ];
+        let trimmed = trim_panic_frames(frames(&symbols));
+        assert_eq!(methods(&trimmed), ["phoenix_code_ide::init::restore_window_state", "unknown"]);
+
+        let untouched = frames(&symbols[3..]);
+        assert_eq!(methods(&trim_panic_frames(untouched.clone())), methods(&untouched));
+    }
+
+    #[test]
+    fn grouping_hash_ignores_frame_line_numbers() {
+        let trimmed = trim_panic_frames(frames(LEGACY_UNWRAP));
+        let hash = grouping_hash(Some("src/boot_config.rs:42"), &trimmed);
+
+        let mut moved = trimmed.clone();
+        for frame in moved.iter_mut() {
+            frame.line_number += 10;
+            frame.column_number += 3;
+        }
+        assert_eq!(grouping_hash(Some("src/boot_config.rs:42"), &moved), hash);
+
+        let mut renamed = trimmed.clone();
+        renamed[0].method = "phoenix_code_ide::boot_config::load_boot_config".to_string();
+        assert_ne!(grouping_hash(Some("src/boot_config.rs:42"), &renamed), hash);
+        assert_ne!(grouping_hash(Some("src/boot_config.rs:43"), &trimmed), hash);
+    }
+}
@@ -0,0 +1,615 @@
+// Crash reporting. A crash is captured as a `CrashReport` and handed to the sink configured in
+// the boot config (`crash_reporting`), which is off by default:
+// - bugsnag (bugsnag.rs): Bugsnag's error reporting api, or a compatible server
+// - http (http_sink.rs): a Sentry envelope posted to any endpoint, for self hosted collectors
+// - localFile: reports are only kept as files in app_local_data_dir
+// Fatal errors of the web app are reported through the same pipeline with `report_js_error`.
+// Reports carry the breadcrumbs of breadcrumbs.rs, the threads of the process and some app state.
+// Reports for sinks that upload are spooled to disk at crash time and sent on the next launch,
+// see crash_spool.rs.
+
+use serde::{Serialize, Deserialize};
+use aes_gcm::aead::{OsRng, rand_core::RngCore};
+use backtrace::{self, Symbol};
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+extern crate chrono;
+use chrono::prelude::*;
+use std::env;
+use tauri::Manager;
+use crate::boot_config::{CrashReportingConfig, CrashReportSinkKind, APP_CONSTANTS};
+use crate::breadcrumbs::{self, Breadcrumb};
+use crate::storage::Storage;
+use crate::bugsnag::BugsnagSink;
+use crate::http_sink::HttpSink;
+use crate::crash_spool::{self, UploadTarget};
+use crate::crash_frames::{grouping_hash, normalize_symbol, trim_panic_frames};
+use crate::js_stack::{parse_js_stack, redact_error_message, redact_script_url};
+
+static LOCAL_CRASH_REPORTS_DIR_NAME: &'static str = "crash_reports_local";
+/// JS errors reported past this many in a run are dropped, so that an error in a loop doesn't
+/// fill the spool.
+const MAX_JS_REPORTS_PER_RUN: usize = 10;
+const MAX_JS_MESSAGE_CHARS: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonFrame {
+    pub file: String,
+    pub line_number: u32,
+    pub column_number: u32,
+    pub method: String,
+    pub in_project: bool,
+    pub code: HashMap<u32, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadInfo {
+    pub id: String,
+    pub name: String,
+    /// True for the thread that crashed.
+    pub error_reporting_thread: bool,
+    /// Innermost frame first. Only known for the thread that crashed.
+    pub stacktrace: Vec<JsonFrame>,
+}
+
+/// A crash, independent of the format any sink sends it in.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CrashReport {
+    pub error_class: String,
+    pub message: String,
+    /// Innermost frame first.
+    pub stacktrace: Vec<JsonFrame>,
+    /// "rust" for panics.
+    pub error_type: String,
+    pub context: String,
+    pub grouping_hash: String,
+    pub unhandled: bool,
+    /// "error", "warning" or "info".
+    pub severity: String,
+    /// ISO 8601 time of the crash.
+    pub time: String,
+    pub app_version: String,
+    pub release_stage: String,
+    pub os_name: String,
+    pub os_version: String,
+    pub arch: String,
+    // defaults for reports spooled by builds without them
+    /// Oldest first.
+    #[serde(default)]
+    pub breadcrumbs: Vec<Breadcrumb>,
+    #[serde(default)]
+    pub threads: Vec<ThreadInfo>,
+    /// Tab name -> json object of values, like "app" -> { "windowCount": 2 }.
+    #[serde(default)]
+    pub meta_data: BTreeMap<String, Value>,
+    /// Random id made when the report is created, so that every attempt to send it carries the
+    /// same id and the server can drop duplicates. Empty in reports spooled without one.
+    #[serde(default)]
+    pub id: String,
+}
+
+fn new_report_id() -> String {
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    hex::encode(id)
+}
+
+/// A destination for crash reports.
+pub trait CrashReportSink: Send + Sync {
+    /// Where reports are posted, None if they are only kept on disk.
+    fn upload_target(&self) -> Option<UploadTarget>;
+    /// Encodes a report in the format the sink stores or sends.
+    fn encode(&self, report: &CrashReport) -> Result<Vec<u8>, String>;
+}
+
+/// Keeps reports as pretty printed json files, for users who don't want anything sent.
+pub struct LocalFileSink;
+
+impl CrashReportSink for LocalFileSink {
+    fn upload_target(&self) -> Option<UploadTarget> {
+        None
+    }
+
+    fn encode(&self, report: &CrashReport) -> Result<Vec<u8>, String> {
+        serde_json::to_vec_pretty(report).map_err(|e| e.to_string())
+    }
+}
+
+pub fn sink_from_config(config: &CrashReportingConfig) -> Result<Box<dyn CrashReportSink>, String> {
+    match config.sink {
+        CrashReportSinkKind::LocalFile => Ok(Box::new(LocalFileSink)),
+        CrashReportSinkKind::Bugsnag => {
+            let api_key = config.api_key.clone().ok_or("The bugsnag crash report sink needs an api_key")?;
+            Ok(Box::new(BugsnagSink::new(config.endpoint.clone(), api_key)))
+        }
+        CrashReportSinkKind::Http => {
+            let endpoint = config.endpoint.clone().ok_or("The http crash report sink needs an endpoint")?;
+            Ok(Box::new(HttpSink::new(endpoint, config.api_key.clone())))
+        }
+    }
+}
+
+// None when crash reporting is off or misconfigured. Unset until `init`, so crashes before that
+// aren't reported.
+static CRASH_REPORT_SINK: OnceCell<Option<Box<dyn CrashReportSink>>> = OnceCell::new();
+// For the metadata of reports.
+static APP_HANDLE: OnceCell<tauri::AppHandle> = OnceCell::new();
+static START_TIME: OnceCell<Instant> = OnceCell::new();
+static JS_REPORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+// The user's answer to sending reports, asked on the first native panic or JS error that would
+// be sent and kept for the run.
+static REPORT_CONSENT: OnceCell<bool> = OnceCell::new();
+
+/// Sets up the sink from the boot config. Only the first call has an effect.
+pub fn init(config: &CrashReportingConfig, app: tauri::AppHandle) {
+    let _ = START_TIME.set(Instant::now());
+    let _ = APP_HANDLE.set(app);
+    let sink = if config.enabled {
+        match sink_from_config(config) {
+            Ok(sink) => Some(sink),
+            Err(e) => {
+                eprintln!("Crash reporting disabled: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let _ = CRASH_REPORT_SINK.set(sink);
+}
+
+fn sink() -> Option<&'static dyn CrashReportSink> {
+    CRASH_REPORT_SINK.get().and_then(|sink| sink.as_deref())
+}
+
+pub fn is_enabled() -> bool {
+    sink().is_some()
+}
+
+/// True if reports leave the machine, in which case the user is asked before one is made.
+pub fn sends_reports() -> bool {
+    sink().map_or(false, |sink| sink.upload_target().is_some())
+}
+
+/// The user's answer to sending reports this run, None if they haven't been asked yet.
+pub fn report_consent() -> Option<bool> {
+    REPORT_CONSENT.get().copied()
+}
+
+/// Keeps the user's answer to sending reports for the rest of the run. An earlier answer wins.
+pub fn set_report_consent(consent: bool) {
+    let _ = REPORT_CONSENT.set(consent);
+}
+
+/// Redacts paths that are not recognized as well-known system paths.
+pub fn redact_path_for_privacy(path: &str) -> String {
+    let known_paths = [
+        // Linux common paths
+        "/usr", "/lib", "/var", "/etc", "/bin", "/sbin", "/dev", "/opt", "/boot", "/sys", "/proc",
+        "/run", "/tmp", "/root",
+
+        // macOS common paths
+        "/Applications", "/System", "/Library", "/private", "/opt", "/sbin",
+        "/usr/local",
+
+        // Windows common paths
+        "C:\\Windows", "C:\\Windows\\System32", "C:\\Program Files", "C:\\Program Files (x86)",
+        "C:\\ProgramData",
+    ];
+    let path_obj = Path::new(path);
+
+    if known_paths.iter().any(|&p| path.starts_with(p)) {
+        path.to_string()
+    } else {
+        format!("{} (path redacted for privacy)", path_obj.file_name().unwrap_or_default().to_str().unwrap_or("Unknown"))
+    }
+}
+
+// The function `from_symbol` now returns a JSON string
+pub fn from_symbol(trace: &Symbol) -> JsonFrame
+{
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+     let path = trace.filename()
+        .and_then(|p| p.to_str())  // Convert Path to &str as soon as possible
+        .unwrap_or("");  // Deal with None cases early, providing a default &str
+
+     let file = if path.starts_with(manifest_dir) {
+         Path::new(path)
+             .strip_prefix(manifest_dir)
+             .map(|p| p.to_str().unwrap_or("/pathRedacted/Unknown"))
+             .unwrap_or("/pathRedacted/Unknown")  // Use .unwrap_or here
+             .to_string()
+     } else {
+         redact_path_for_privacy(path)
+     };
+    let linenumber = trace.lineno().unwrap_or(0);
+    let columnnumber = trace.colno().unwrap_or(0);
+    let method = trace.name()
+        .map(|name| normalize_symbol(&name.to_string()))
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // Example hardcoded code snippet, this part should be dynamically populated based on your context
+    let code_snippet = std::collections::HashMap::new();
+    // code_snippet.insert(1, "def a".to_string()); // add here is there are code snippets to show
+
+    JsonFrame {
+        file: file.to_string(),
+        line_number: linenumber,
+        column_number: columnnumber,
+        method: method.clone(),
+        in_project: path.starts_with(manifest_dir),
+        code: code_snippet,
+    }
+}
+
+/// Where a panic was raised. Paths outside the project are redacted like frame paths.
+#[derive(Clone, Debug)]
+pub struct PanicLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl PanicLocation {
+    pub fn new(location: &std::panic::Location) -> Self {
+        let file = location.file();
+        let file = if Path::new(file).is_absolute() { redact_path_for_privacy(file) } else { file.to_string() };
+        PanicLocation { file, line: location.line(), column: location.column() }
+    }
+
+    /// "file:line", what crashes are grouped by. The column is left out as it shifts with
+    /// formatting changes.
+    pub fn file_and_line(&self) -> String {
+        format!("{}:{}", self.file, self.line)
+    }
+}
+
+impl std::fmt::Display for PanicLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// What the panic hook knows about a panic, for the crash dialog and the report.
+#[derive(Clone, Debug)]
+pub struct PanicDetails {
+    pub message: String,
+    pub location: Option<PanicLocation>,
+    pub thread_name: String,
+}
+
+impl PanicDetails {
+    /// Takes the payload and location of the `PanicInfo` given to the panic hook. `panic!` with
+    /// a literal message has a `&str` payload, formatted messages and `expect` have a `String`.
+    pub fn new(payload: &(dyn std::any::Any + Send), location: Option<&std::panic::Location>) -> Self {
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "No specific error message available".to_string()
+        };
+        PanicDetails {
+            message,
+            location: location.map(PanicLocation::new),
+            thread_name: std::thread::current().name().unwrap_or("unnamed").to_string(),
+        }
+    }
+
+    /// "'message' in thread 'main' at src/main.rs:10:5"
+    pub fn describe(&self) -> String {
+        let mut description = format!("'{}' in thread '{}'", self.message, self.thread_name);
+        if let Some(location) = &self.location {
+            description.push_str(&format!(" at {}", location));
+        }
+        description
+    }
+}
+
+fn get_current_time() -> String {
+    // Get the current UTC time
+    let now = Utc::now();
+
+    // Format the time to a string in ISO 8601 format with milliseconds
+    now.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn get_sysinfo() -> (String, String, String, String) {
+    let info = os_info::get();
+    let os_type = info.os_type().to_string();
+    let os_release = info.version().to_string();
+
+    let arch = info.architecture().unwrap_or("unknown").to_string();
+    let exe_path = env::args().next().unwrap_or_else(|| "Unknown Executable".to_string());
+    let exe_name = Path::new(&exe_path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("Unknown Executable")
+        .to_string();
+
+    (os_type, os_release, arch, exe_name)
+}
+
+fn spool_dir() -> Option<PathBuf> {
+    APP_CONSTANTS.get().map(|app_constants| crash_spool::spool_dir(&app_constants.app_local_data_dir))
+}
+
+fn local_reports_dir() -> Option<PathBuf> {
+    APP_CONSTANTS.get().map(|app_constants| app_constants.app_local_data_dir.join(LOCAL_CRASH_REPORTS_DIR_NAME))
+}
+
+/// Saves a report for the configured sink: spooled to be sent on the next launch, or kept in
+/// the local reports folder. Does nothing if crash reporting is off. Returns true if saved.
+pub fn save_report(report: &CrashReport) -> bool {
+    let Some(sink) = sink() else {
+        return false;
+    };
+    let saved = if sink.upload_target().is_some() {
+        // spooled in our own format, the sink encodes it when it is sent
+        serde_json::to_vec(report).map_err(|e| e.to_string())
+            .and_then(|body| crash_spool::spool_report(&spool_dir().ok_or("App constants not initialized")?, &body))
+    } else {
+        sink.encode(report)
+            .and_then(|body| crash_spool::spool_report(&local_reports_dir().ok_or("App constants not initialized")?, &body))
+    };
+    match saved {
+        Ok(report_path) => {
+            println!("Crash report saved to {}", report_path.display());
+            true
+        }
+        Err(e) => {
+            eprintln!("Failed to save crash report: {}", e);
+            false
+        }
+    }
+}
+
+// The id of the current thread as listed in /proc/self/task.
+#[cfg(target_os = "linux")]
+fn current_thread_id() -> Option<String> {
+    let thread_path = std::fs::read_link("/proc/thread-self").ok()?;
+    thread_path.file_name().map(|tid| tid.to_string_lossy().to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_thread_id() -> Option<String> {
+    None
+}
+
+// Only the crashing thread has a stack. Rust can't walk the stacks of other threads without
+// stopping them from a signal handler, which isn't worth the risk inside a panic hook, so they are
+// only listed by name, on linux where /proc has them.
+#[cfg(target_os = "linux")]
+fn other_threads(current_thread_id: Option<&str>) -> Vec<ThreadInfo> {
+    let Ok(tasks) = std::fs::read_dir("/proc/self/task") else {
+        return Vec::new();
+    };
+    tasks.filter_map(|task| task.ok())
+        .filter_map(|task| {
+            let id = task.file_name().to_string_lossy().to_string();
+            if Some(id.as_str()) == current_thread_id {
+                return None;
+            }
+            let name = std::fs::read_to_string(task.path().join("comm"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_default();
+            Some(ThreadInfo { id, name, error_reporting_thread: false, stacktrace: Vec::new() })
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn other_threads(_current_thread_id: Option<&str>) -> Vec<ThreadInfo> {
+    Vec::new()
+}
+
+/// The crashing thread with `stacktrace` first, then the other threads of the process.
+pub fn collect_threads(stacktrace: &[JsonFrame]) -> Vec<ThreadInfo> {
+    let current = std::thread::current();
+    let current_id = current_thread_id();
+    let mut threads = vec![ThreadInfo {
+        id: current_id.clone().unwrap_or_else(|| format!("{:?}", current.id())),
+        name: current.name().unwrap_or("unnamed").to_string(),
+        error_reporting_thread: true,
+        stacktrace: stacktrace.to_vec(),
+    }];
+    threads.extend(other_threads(current_id.as_deref()));
+    threads
+}
+
+/// App state worth knowing when triaging a crash. Runs in the panic hook, so it skips what is
+/// locked instead of waiting.
+pub fn collect_meta_data() -> BTreeMap<String, Value> {
+    let mut app_tab = serde_json::Map::new();
+    if let Some(window_count) = breadcrumbs::open_window_count() {
+        app_tab.insert("windowCount".to_string(), json!(window_count));
+    }
+    if let Some(start_time) = START_TIME.get() {
+        app_tab.insert("uptimeMs".to_string(), json!(start_time.elapsed().as_millis() as u64));
+    }
+    let mut meta_data = BTreeMap::new();
+    meta_data.insert("app".to_string(), Value::Object(app_tab));
+    let storage_size = APP_HANDLE.get()
+        .and_then(|app| app.try_state::<Storage>())
+        .and_then(|storage| storage.try_size());
+    if let Some((key_count, total_bytes)) = storage_size {
+        meta_data.insert("storage".to_string(), json!({ "keyCount": key_count, "totalBytes": total_bytes }));
+    }
+    meta_data
+}
+
+pub fn handle(panic_details: &PanicDetails){
+    let mut result: Vec<JsonFrame> = Vec::new();
+    backtrace::trace(|frame| {
+        backtrace::resolve(frame.ip(), |symbol| {
+            result.push(from_symbol(&symbol))
+        });
+        true
+    });
+    let result = trim_panic_frames(result);
+    let (os_type, os_version, arch, exe_name) = get_sysinfo();
+    let location = panic_details.location.as_ref();
+    let mut meta_data = collect_meta_data();
+    meta_data.insert("panic".to_string(), json!({
+        "thread": panic_details.thread_name,
+        "location": location.map(|location| location.to_string()),
+    }));
+    let report = CrashReport {
+        error_class: "Rust_Panic".to_string(),
+        message: panic_details.message.clone(),
+        stacktrace: result.clone(),
+        error_type: "rust".to_string(),
+        context: location.map_or_else(|| panic_details.message.clone(), |location| location.to_string()),
+        grouping_hash: grouping_hash(location.map(|location| location.file_and_line()).as_deref(), &result),
+        unhandled: true,
+        severity: "error".to_string(),
+        time: get_current_time(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        release_stage: exe_name,
+        os_name: os_type,
+        os_version,
+        arch,
+        breadcrumbs: breadcrumbs::snapshot(),
+        threads: collect_threads(&result),
+        meta_data,
+        id: new_report_id(),
+    };
+    save_report(&report);
+}
+
+/// A fatal error of the web app, like an unhandled rejection during boot.
+pub struct JsError {
+    pub error_class: String,
+    pub message: String,
+    /// The `stack` of the JS error.
+    pub stack: String,
+    /// "error", "warning" or "info".
+    pub severity: String,
+}
+
+fn truncate_message(message: &str) -> String {
+    match message.char_indices().nth(MAX_JS_MESSAGE_CHARS) {
+        Some((cut, _)) => format!("{}...", &message[..cut]),
+        None => message.to_string(),
+    }
+}
+
+/// Reports a JS error from the window `window_label` at `window_url`, with the redaction and
+/// sinks of native crashes. If the report would be sent and the user hasn't answered yet this
+/// run, `ask_consent` is called with a description of the error to ask them. Concurrent reports
+/// wait for that answer instead of asking again. Blocks while asking, so it must not run on the
+/// main thread. Returns true if a report was saved.
+pub fn report_js_error<F: FnOnce(&str) -> bool>(js_error: JsError, window_label: &str, window_url: &str,
+                                               ask_consent: F) -> Result<bool, String> {
+    if !["error", "warning", "info"].contains(&js_error.severity.as_str()) {
+        return Err(format!("Unknown severity {}, expected error, warning or info", js_error.severity));
+    }
+    // only the class, the message can hold file contents or tokens and the breadcrumb outlives
+    // the user's answer for this report
+    breadcrumbs::leave(breadcrumbs::BreadcrumbType::Error, &js_error.error_class);
+    if !is_enabled() {
+        return Ok(false);
+    }
+    if JS_REPORT_COUNT.fetch_add(1, Ordering::Relaxed) >= MAX_JS_REPORTS_PER_RUN {
+        return Ok(false);
+    }
+    let message = truncate_message(&redact_error_message(&js_error.message));
+    if sends_reports() {
+        let description = format!("{}: {}", js_error.error_class, message);
+        if !*REPORT_CONSENT.get_or_init(|| ask_consent(&description)) {
+            return Ok(false);
+        }
+    }
+
+    let stacktrace = parse_js_stack(&js_error.stack);
+    let location = stacktrace.first().map(|frame| format!("{}:{}", frame.file, frame.line_number));
+    let (os_type, os_version, arch, exe_name) = get_sysinfo();
+    let mut meta_data = collect_meta_data();
+    meta_data.insert("window".to_string(), json!({
+        "label": window_label,
+        "url": redact_script_url(window_url),
+    }));
+    let report = CrashReport {
+        error_class: js_error.error_class,
+        message: message.clone(),
+        grouping_hash: grouping_hash(location.as_deref(), &stacktrace),
+        context: location.unwrap_or(message),
+        stacktrace,
+        error_type: "browserjs".to_string(),
+        unhandled: true,
+        severity: js_error.severity,
+        time: get_current_time(),
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        release_stage: exe_name,
+        os_name: os_type,
+        os_version,
+        arch,
+        breadcrumbs: breadcrumbs::snapshot(),
+        // the native threads say nothing about a JS error
+        threads: Vec::new(),
+        meta_data,
+        id: new_report_id(),
+    };
+    Ok(save_report(&report))
+}
+
+/// Sends the crash reports spooled by earlier runs to the configured sink. Called at startup.
+pub async fn upload_spooled_reports() {
+    let (Some(sink), Some(spool_dir)) = (sink(), spool_dir()) else {
+        return;
+    };
+    let Some(upload_target) = sink.upload_target() else {
+        return;
+    };
+    let sent = crash_spool::upload_spooled_reports(&spool_dir, &upload_target, |body| {
+        let report: CrashReport = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+        sink.encode(&report)
+    }).await;
+    if sent > 0 {
+        println!("Sent {} crash reports from previous runs", sent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declined_js_report_leaves_no_message_in_breadcrumbs() {
+        let sink = sink_from_config(&CrashReportingConfig {
+            enabled: true,
+            sink: CrashReportSinkKind::Http,
+            endpoint: Some("https://collector.invalid/api/1/envelope/".to_string()),
+            api_key: None,
+        }).unwrap();
+        let _ = CRASH_REPORT_SINK.set(Some(sink));
+        let js_error = JsError {
+            error_class: "TypeError".to_string(),
+            message: "token=s3cret-value while reading /home/jane/notes.txt".to_string(),
+            stack: String::new(),
+            severity: "error".to_string(),
+        };
+        let mut asked = None;
+        let saved = report_js_error(js_error, "main", "phtauri://localhost/", |description| {
+            asked = Some(description.to_string());
+            false
+        }).unwrap();
+
+        assert!(!saved);
+        let asked = asked.expect("the user should be asked before a report is sent");
+        assert!(!asked.contains("/home/jane"));
+        let breadcrumbs = breadcrumbs::snapshot();
+        assert!(breadcrumbs.iter().any(|breadcrumb| breadcrumb.name == "TypeError"));
+        for breadcrumb in breadcrumbs {
+            assert!(!breadcrumb.name.contains("s3cret"));
+            assert!(breadcrumb.meta_data.values().all(|value| !value.contains("s3cret") && !value.contains("notes.txt")));
+        }
+    }
+}
@@ -0,0 +1,405 @@
+// Spool of crash reports that still have to be uploaded. At panic time the report is only
+// written to a file under app_local_data_dir, so a crash never waits on the network and reports
+// made while offline aren't lost. The spool is uploaded on the next launch, with retries and
+// backoff. It is capped in size and reports that stay stuck for too long are dropped.
+
+use reqwest::header::HeaderMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use crate::utilities::{unix_time_millis, write_file_atomic};
+
+static CRASH_SPOOL_DIR_NAME: &'static str = "crash_reports";
+const REPORT_EXTENSION: &str = "json";
+const MAX_SPOOL_BYTES: u64 = 10 * 1024 * 1024;
+const MAX_REPORT_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const UPLOAD_ATTEMPTS: u32 = 4;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(2);
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How hard an upload is tried before the report is kept for the next launch.
+struct RetryPolicy {
+    attempts: u32,
+    /// Doubled after every failed attempt.
+    first_retry_delay: Duration,
+    timeout: Duration,
+}
+
+const DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy {
+    attempts: UPLOAD_ATTEMPTS,
+    first_retry_delay: FIRST_RETRY_DELAY,
+    timeout: UPLOAD_TIMEOUT,
+};
+
+// Keeps the names of reports spooled in the same millisecond apart.
+static REPORT_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+pub fn spool_dir(app_local_data_dir: &Path) -> PathBuf {
+    app_local_data_dir.join(CRASH_SPOOL_DIR_NAME)
+}
+
+struct SpooledReport {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+// Oldest first.
+fn list_reports(dir: &Path) -> Vec<SpooledReport> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut reports: Vec<SpooledReport> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == REPORT_EXTENSION))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some(SpooledReport {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+        })
+        .collect();
+    reports.sort_by(|a, b| a.modified.cmp(&b.modified).then_with(|| a.path.cmp(&b.path)));
+    reports
+}
+
+fn remove_report(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        eprintln!("Failed to remove crash report {}: {}", path.display(), e);
+    }
+}
+
+/// Drops reports older than the max age, then the oldest reports until the spool fits the size
+/// cap with `incoming_bytes` more.
+fn prune(dir: &Path, incoming_bytes: u64) {
+    let now = SystemTime::now();
+    let mut total_bytes = 0;
+    let mut kept = Vec::new();
+    for report in list_reports(dir) {
+        let age = now.duration_since(report.modified).unwrap_or_default();
+        if age > MAX_REPORT_AGE {
+            remove_report(&report.path);
+        } else {
+            total_bytes += report.size;
+            kept.push(report);
+        }
+    }
+    for report in kept {
+        if total_bytes + incoming_bytes <= MAX_SPOOL_BYTES {
+            break;
+        }
+        total_bytes -= report.size;
+        remove_report(&report.path);
+    }
+}
+
+/// Writes a report to the spool. Runs in the panic hook, so it only touches the file system.
+pub fn spool_report(dir: &Path, body: &[u8]) -> Result<PathBuf, String> {
+    if body.len() as u64 > MAX_SPOOL_BYTES {
+        return Err(format!("Crash report of {} bytes is too big to spool", body.len()));
+    }
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    prune(dir, body.len() as u64);
+    let report_path = dir.join(format!("{}-{}-{}.{}", unix_time_millis(), std::process::id(),
+        REPORT_SEQUENCE.fetch_add(1, Ordering::Relaxed), REPORT_EXTENSION));
+    write_file_atomic(&report_path, body).map_err(|e| e.to_string())?;
+    Ok(report_path)
+}
+
+/// Where spooled reports are posted.
+pub struct UploadTarget {
+    pub url: String,
+    pub headers: HeaderMap,
+}
+
+enum UploadOutcome {
+    Sent,
+    /// The endpoint won't ever take this report, like for a malformed payload.
+    Rejected(String),
+    /// Offline or the endpoint is having trouble, try again later.
+    Failed(String),
+}
+
+async fn upload_once(client: &reqwest::Client, target: &UploadTarget, body: Vec<u8>) -> UploadOutcome {
+    let response = match client.post(&target.url).headers(target.headers.clone()).body(body).send().await {
+        Ok(response) => response,
+        Err(e) => return UploadOutcome::Failed(e.to_string()),
+    };
+    let status = response.status();
+    if status.is_success() {
+        UploadOutcome::Sent
+    } else if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+        && status != reqwest::StatusCode::REQUEST_TIMEOUT {
+        UploadOutcome::Rejected(status.to_string())
+    } else {
+        UploadOutcome::Failed(status.to_string())
+    }
+}
+
+/// Uploads the spooled reports, oldest first, posting each to `target` as encoded by `encode`.
+/// Reports are deleted once accepted or rejected for good. Stops at the first report that can't
+/// be sent after all retries, keeping the rest for the next launch. Returns the number sent.
+pub async fn upload_spooled_reports<F>(dir: &Path, target: &UploadTarget, encode: F) -> usize
+    where F: Fn(&[u8]) -> Result<Vec<u8>, String> {
+    upload_with_retries(dir, target, encode, &DEFAULT_RETRY_POLICY).await
+}
+
+async fn upload_with_retries<F>(dir: &Path, target: &UploadTarget, encode: F, retry_policy: &RetryPolicy) -> usize
+    where F: Fn(&[u8]) -> Result<Vec<u8>, String> {
+    prune(dir, 0);
+    let reports = list_reports(dir);
+    if reports.is_empty() {
+        return 0;
+    }
+    let client = match reqwest::Client::builder().timeout(retry_policy.timeout).build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to create crash report upload client: {}", e);
+            return 0;
+        }
+    };
+    let mut sent = 0;
+    for report in reports {
+        let body = match fs::read(&report.path).map_err(|e| e.to_string()).and_then(|spooled| encode(&spooled)) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Dropping unreadable crash report {}: {}", report.path.display(), e);
+                remove_report(&report.path);
+                continue;
+            }
+        };
+        let mut delay = retry_policy.first_retry_delay;
+        let mut attempt = 1;
+        loop {
+            match upload_once(&client, target, body.clone()).await {
+                UploadOutcome::Sent => {
+                    sent += 1;
+                    remove_report(&report.path);
+                    break;
+                }
+                UploadOutcome::Rejected(reason) => {
+                    eprintln!("Crash report {} rejected: {}", report.path.display(), reason);
+                    remove_report(&report.path);
+                    break;
+                }
+                UploadOutcome::Failed(reason) if attempt < retry_policy.attempts => {
+                    eprintln!("Crash report upload failed, retrying in {:?}: {}", delay, reason);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                UploadOutcome::Failed(reason) => {
+                    eprintln!("Crash report upload failed, keeping reports for the next launch: {}", reason);
+                    return sent;
+                }
+            }
+        }
+    }
+    sent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::test_dir;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const TEST_RETRY_POLICY: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        first_retry_delay: Duration::from_millis(50),
+        timeout: Duration::from_millis(300),
+    };
+
+    /// What the test endpoint does with a request.
+    #[derive(Clone, Copy)]
+    enum Reply {
+        Status(u16),
+        /// Never answers, so the client times out.
+        Hang,
+    }
+
+    struct Request {
+        body: Vec<u8>,
+        received_at: Instant,
+    }
+
+    fn set_age(path: &Path, age: Duration) {
+        let file = fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    async fn read_request(stream: &mut tokio::net::TcpStream) -> Vec<u8> {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            if read == 0 {
+                return Vec::new();
+            }
+            request.extend_from_slice(&buffer[..read]);
+            let Some(header_end) = request.windows(4).position(|window| window == b"\r\n\r\n") else {
+                continue;
+            };
+            let headers = String::from_utf8_lossy(&request[..header_end]).to_lowercase();
+            let content_length: usize = headers.lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            if request.len() >= header_end + 4 + content_length {
+                return request[header_end + 4..header_end + 4 + content_length].to_vec();
+            }
+        }
+    }
+
+    /// Serves `replies` in order, the last one for every request after that. Returns the url and
+    /// the requests received.
+    async fn start_endpoint(replies: Vec<Reply>) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/report", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let received = received.clone();
+                let replies = replies.clone();
+                tokio::spawn(async move {
+                    let body = read_request(&mut stream).await;
+                    let index = {
+                        let mut received = received.lock().unwrap();
+                        received.push(Request { body, received_at: Instant::now() });
+                        received.len() - 1
+                    };
+                    match replies[index.min(replies.len() - 1)] {
+                        Reply::Status(status) => {
+                            let response = format!("HTTP/1.1 {} Test\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                            let _ = stream.write_all(response.as_bytes()).await;
+                        }
+                        Reply::Hang => tokio::time::sleep(Duration::from_secs(10)).await,
+                    }
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    async fn upload(dir: &Path, url: String) -> usize {
+        let target = UploadTarget { url, headers: HeaderMap::new() };
+        upload_with_retries(dir, &target, |spooled| Ok(spooled.to_vec()), &TEST_RETRY_POLICY).await
+    }
+
+    #[tokio::test]
+    async fn successful_upload_deletes_the_report() {
+        let dir = test_dir("crash-spool-success");
+        let report_path = spool_report(&dir, b"report-1").unwrap();
+        let (url, requests) = start_endpoint(vec![Reply::Status(200)]).await;
+
+        assert_eq!(upload(&dir, url).await, 1);
+        assert!(!report_path.exists());
+        assert_eq!(requests.lock().unwrap()[0].body, b"report-1");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_with_backoff() {
+        let dir = test_dir("crash-spool-retry");
+        let report_path = spool_report(&dir, b"report-1").unwrap();
+        let (url, requests) = start_endpoint(vec![Reply::Status(503), Reply::Status(500), Reply::Status(200)]).await;
+
+        assert_eq!(upload(&dir, url).await, 1);
+        assert!(!report_path.exists());
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        let first_delay = requests[1].received_at - requests[0].received_at;
+        let second_delay = requests[2].received_at - requests[1].received_at;
+        assert!(first_delay >= TEST_RETRY_POLICY.first_retry_delay);
+        assert!(second_delay >= TEST_RETRY_POLICY.first_retry_delay * 2);
+        drop(requests);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeouts_are_retried() {
+        let dir = test_dir("crash-spool-timeout");
+        let report_path = spool_report(&dir, b"report-1").unwrap();
+        let (url, requests) = start_endpoint(vec![Reply::Hang, Reply::Status(200)]).await;
+
+        assert_eq!(upload(&dir, url).await, 1);
+        assert!(!report_path.exists());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_are_kept_for_the_next_launch() {
+        let dir = test_dir("crash-spool-keep");
+        let first = spool_report(&dir, b"report-1").unwrap();
+        set_age(&first, Duration::from_secs(60));
+        let second = spool_report(&dir, b"report-2").unwrap();
+        let (url, requests) = start_endpoint(vec![Reply::Status(502)]).await;
+
+        assert_eq!(upload(&dir, url).await, 0);
+        assert!(first.exists());
+        assert!(second.exists());
+        // gives up on the first report without trying the rest
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), TEST_RETRY_POLICY.attempts as usize);
+        assert!(requests.iter().all(|request| request.body == b"report-1"));
+        drop(requests);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_reports_are_dropped() {
+        let dir = test_dir("crash-spool-rejected");
+        let first = spool_report(&dir, b"report-1").unwrap();
+        set_age(&first, Duration::from_secs(60));
+        let second = spool_report(&dir, b"report-2").unwrap();
+        let (url, requests) = start_endpoint(vec![Reply::Status(400), Reply::Status(200)]).await;
+
+        assert_eq!(upload(&dir, url).await, 1);
+        assert!(!first.exists());
+        assert!(!second.exists());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spool_is_capped_in_size() {
+        let dir = test_dir("crash-spool-size-cap");
+        let report = vec![b'x'; (MAX_SPOOL_BYTES / 3) as usize];
+        let mut paths = Vec::new();
+        for index in 0..4 {
+            let path = spool_report(&dir, &report).unwrap();
+            set_age(&path, Duration::from_secs(60 * (10 - index)));
+            paths.push(path);
+        }
+        // the fourth report only fits once the oldest is gone
+        assert!(!paths[0].exists());
+        assert!(paths[1..].iter().all(|path| path.exists()));
+
+        let too_big = vec![b'x'; MAX_SPOOL_BYTES as usize + 1];
+        assert!(spool_report(&dir, &too_big).is_err());
+        assert!(paths[1..].iter().all(|path| path.exists()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn old_reports_are_pruned() {
+        let dir = test_dir("crash-spool-age");
+        let old = spool_report(&dir, b"old").unwrap();
+        set_age(&old, MAX_REPORT_AGE + Duration::from_secs(60));
+        let recent = spool_report(&dir, b"recent").unwrap();
+        set_age(&recent, MAX_REPORT_AGE - Duration::from_secs(60));
+
+        prune(&dir, 0);
+        assert!(!old.exists());
+        assert!(recent.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
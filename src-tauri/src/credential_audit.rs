@@ -0,0 +1,282 @@
+// Append-only audit log of credential access, for security reviews. Every credential command,
+// including calls denied by the origin policy, is recorded with the calling
+// window and its url as one json line in app_local_data_dir. Secret values are never logged.
+// The log is rotated once it gets too big, keeping a few older files. The files are owner-only,
+// as they tell which windows and urls used which credential scopes.
+
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::boot_config::APP_CONSTANTS;
+use crate::utilities::unix_time_millis;
+
+static AUDIT_LOG_FILE_NAME: &'static str = "credential_audit.log";
+const MAX_AUDIT_LOG_BYTES: u64 = 1024 * 1024;
+/// Number of rotated files kept besides the current one, named ".1" (newest) to ".3".
+const ROTATED_AUDIT_LOGS: usize = 3;
+const DEFAULT_QUERY_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditCommand {
+    Store,
+    Get,
+    Delete,
+    DeleteAll,
+    List,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditOutcome {
+    Success,
+    NotFound,
+    /// Blocked before running, by the origin policy or for lack of window trust.
+    Denied,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+    pub window_label: String,
+    pub window_url: String,
+    pub command: AuditCommand,
+    /// None for commands that aren't about one scope.
+    pub scope_name: Option<String>,
+    pub outcome: AuditOutcome,
+    pub error: Option<String>,
+}
+
+/// Filters for `query`. Unset fields match everything.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AuditFilter {
+    pub scope_name: Option<String>,
+    pub window_label: Option<String>,
+    pub command: Option<AuditCommand>,
+    pub outcome: Option<AuditOutcome>,
+    /// Only entries at or after this unix time in milliseconds.
+    pub since: Option<u64>,
+    /// Most entries to return, newest first. Defaults to 100.
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.scope_name.as_ref().map_or(true, |scope_name| entry.scope_name.as_ref() == Some(scope_name))
+            && self.window_label.as_ref().map_or(true, |label| &entry.window_label == label)
+            && self.command.map_or(true, |command| entry.command == command)
+            && self.outcome.map_or(true, |outcome| entry.outcome == outcome)
+            && self.since.map_or(true, |since| entry.timestamp >= since)
+    }
+}
+
+// Serializes appends and rotation.
+static AUDIT_LOG_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Maps a command name to what the audit log records for it, None if it isn't audited.
+pub fn audited_command(command: &str) -> Option<AuditCommand> {
+    match command {
+        "store_credential" => Some(AuditCommand::Store),
+        "get_credential" => Some(AuditCommand::Get),
+        "delete_credential" => Some(AuditCommand::Delete),
+        "delete_all_credentials" => Some(AuditCommand::DeleteAll),
+        "list_credential_scopes" => Some(AuditCommand::List),
+        _ => None,
+    }
+}
+
+fn audit_log_dir() -> Option<PathBuf> {
+    APP_CONSTANTS.get().map(|app_constants| app_constants.app_local_data_dir.clone())
+}
+
+fn audit_log_path(dir: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        dir.join(AUDIT_LOG_FILE_NAME)
+    } else {
+        dir.join(format!("{}.{}", AUDIT_LOG_FILE_NAME, index))
+    }
+}
+
+fn rotate_if_needed(dir: &Path) {
+    let current_path = audit_log_path(dir, 0);
+    let size = fs::metadata(&current_path).map(|metadata| metadata.len()).unwrap_or(0);
+    if size < MAX_AUDIT_LOG_BYTES {
+        return;
+    }
+    // shift .2 -> .3, .1 -> .2, current -> .1, dropping the oldest
+    for index in (1..=ROTATED_AUDIT_LOGS).rev() {
+        let from = audit_log_path(dir, index - 1);
+        if from.exists() {
+            if let Err(e) = fs::rename(&from, audit_log_path(dir, index)) {
+                eprintln!("Failed to rotate credential audit log {}: {}", from.display(), e);
+            }
+        }
+    }
+}
+
+fn append_entry(dir: &Path, entry: &AuditEntry) -> std::io::Result<()> {
+    // serializing a struct of strings and numbers can't fail
+    let mut line = serde_json::to_string(entry).unwrap();
+    line.push('\n');
+    rotate_if_needed(dir);
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]{
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(audit_log_path(dir, 0))?.write_all(line.as_bytes())
+}
+
+/// Appends an entry. Failures are logged, they never fail the credential operation.
+pub fn record<R: tauri::Runtime>(window: &tauri::Window<R>, command: AuditCommand, scope_name: Option<&str>,
+              outcome: AuditOutcome, error: Option<&str>) {
+    let entry = AuditEntry {
+        timestamp: unix_time_millis(),
+        window_label: window.label().to_string(),
+        window_url: window.url().to_string(),
+        command,
+        scope_name: scope_name.map(|scope_name| scope_name.to_string()),
+        outcome,
+        error: error.map(|error| error.to_string()),
+    };
+    let Some(dir) = audit_log_dir() else {
+        return;
+    };
+    let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+    if let Err(e) = append_entry(&dir, &entry) {
+        eprintln!("Failed to write credential audit log in {}: {}", dir.display(), e);
+    }
+}
+
+/// Records the result of a credential command. `found` tells a success from finding no
+/// credential for the scope.
+pub fn record_result<R: tauri::Runtime, T>(window: &tauri::Window<R>, command: AuditCommand, scope_name: Option<&str>,
+                        result: &Result<T, String>, found: impl FnOnce(&T) -> bool) {
+    match result {
+        Ok(value) if found(value) => record(window, command, scope_name, AuditOutcome::Success, None),
+        Ok(_) => record(window, command, scope_name, AuditOutcome::NotFound, None),
+        Err(e) => record(window, command, scope_name, AuditOutcome::Error, Some(e)),
+    }
+}
+
+/// Returns the newest entries matching `filter`, newest first.
+pub fn query(filter: &AuditFilter) -> Vec<AuditEntry> {
+    let Some(dir) = audit_log_dir() else {
+        return Vec::new();
+    };
+    let _guard = AUDIT_LOG_LOCK.lock().unwrap();
+    query_dir(&dir, filter)
+}
+
+fn query_dir(dir: &Path, filter: &AuditFilter) -> Vec<AuditEntry> {
+    let limit = filter.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    let mut matching = Vec::new();
+    // current file first, then the rotated ones from newest to oldest
+    for index in 0..=ROTATED_AUDIT_LOGS {
+        if matching.len() >= limit {
+            break;
+        }
+        let Ok(file) = File::open(audit_log_path(dir, index)) else {
+            continue;
+        };
+        let mut file_entries: Vec<AuditEntry> = BufReader::new(file).lines()
+            .map_while(Result::ok)
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(&line).ok())
+            .filter(|entry| filter.matches(entry))
+            .collect();
+        file_entries.reverse();
+        matching.extend(file_entries.into_iter().take(limit - matching.len()));
+    }
+    matching
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::test_dir;
+
+    fn entry(timestamp: u64, window_label: &str, command: AuditCommand, scope_name: Option<&str>,
+             outcome: AuditOutcome) -> AuditEntry {
+        AuditEntry {
+            timestamp,
+            window_label: window_label.to_string(),
+            window_url: "phtauri://localhost/".to_string(),
+            command,
+            scope_name: scope_name.map(|scope_name| scope_name.to_string()),
+            outcome,
+            error: None,
+        }
+    }
+
+    fn timestamps(entries: &[AuditEntry]) -> Vec<u64> {
+        entries.iter().map(|entry| entry.timestamp).collect()
+    }
+
+    #[test]
+    fn rotation_shifts_files_and_drops_the_oldest() {
+        let dir = test_dir("credential-audit-rotation");
+        fs::write(audit_log_path(&dir, 0), vec![b'x'; MAX_AUDIT_LOG_BYTES as usize - 1]).unwrap();
+        for index in 1..=ROTATED_AUDIT_LOGS {
+            fs::write(audit_log_path(&dir, index), format!("file {}", index)).unwrap();
+        }
+        rotate_if_needed(&dir);
+        assert_eq!(fs::read_to_string(audit_log_path(&dir, 1)).unwrap(), "file 1");
+
+        fs::write(audit_log_path(&dir, 0), "current".repeat(MAX_AUDIT_LOG_BYTES as usize / 7 + 1)).unwrap();
+        rotate_if_needed(&dir);
+        assert!(!audit_log_path(&dir, 0).exists());
+        assert!(fs::read_to_string(audit_log_path(&dir, 1)).unwrap().starts_with("current"));
+        assert_eq!(fs::read_to_string(audit_log_path(&dir, 2)).unwrap(), "file 1");
+        assert_eq!(fs::read_to_string(audit_log_path(&dir, 3)).unwrap(), "file 2");
+        assert!(!audit_log_path(&dir, 4).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn query_filters_newest_first_across_rotated_files() {
+        let dir = test_dir("credential-audit-query");
+        // the rotated file holds the older entries
+        append_entry(&dir, &entry(1, "main", AuditCommand::Get, Some("github"), AuditOutcome::Success)).unwrap();
+        append_entry(&dir, &entry(2, "phcode-1", AuditCommand::Store, Some("github"), AuditOutcome::Success)).unwrap();
+        fs::rename(audit_log_path(&dir, 0), audit_log_path(&dir, 1)).unwrap();
+        append_entry(&dir, &entry(3, "main", AuditCommand::Get, Some("gitlab"), AuditOutcome::NotFound)).unwrap();
+        append_entry(&dir, &entry(4, "main", AuditCommand::Get, Some("github"), AuditOutcome::Denied)).unwrap();
+        append_entry(&dir, &entry(5, "main", AuditCommand::List, None, AuditOutcome::Success)).unwrap();
+
+        assert_eq!(timestamps(&query_dir(&dir, &AuditFilter::default())), [5, 4, 3, 2, 1]);
+        let github = AuditFilter { scope_name: Some("github".to_string()), ..Default::default() };
+        assert_eq!(timestamps(&query_dir(&dir, &github)), [4, 2, 1]);
+        let main_gets = AuditFilter {
+            window_label: Some("main".to_string()),
+            command: Some(AuditCommand::Get),
+            ..Default::default()
+        };
+        assert_eq!(timestamps(&query_dir(&dir, &main_gets)), [4, 3, 1]);
+        let denied = AuditFilter { outcome: Some(AuditOutcome::Denied), ..Default::default() };
+        assert_eq!(timestamps(&query_dir(&dir, &denied)), [4]);
+        let since = AuditFilter { since: Some(3), ..Default::default() };
+        assert_eq!(timestamps(&query_dir(&dir, &since)), [5, 4, 3]);
+        let limited = AuditFilter { limit: Some(4), ..Default::default() };
+        assert_eq!(timestamps(&query_dir(&dir, &limited)), [5, 4, 3, 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn log_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("credential-audit-mode");
+        append_entry(&dir, &entry(1, "main", AuditCommand::Get, Some("github"), AuditOutcome::Success)).unwrap();
+        let mode = fs::metadata(audit_log_path(&dir, 0)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
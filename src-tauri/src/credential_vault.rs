@@ -0,0 +1,276 @@
+// Encrypted file vault used for credentials when there is no usable OS keyring, like on Linux
+// without a Secret Service daemon. All secrets are kept in one AES-256-GCM encrypted file in
+// app_local_data_dir. The key is derived with Argon2id from
+// - a random install secret in a separate owner-only file,
+// - the machine id and the user name, so that a copied vault doesn't open elsewhere,
+// - and an optional user passphrase, in which case the vault has to be unlocked first.
+
+use aes_gcm::{AeadCore, Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit, OsRng, rand_core::RngCore};
+use argon2::Argon2;
+use once_cell::sync::Lazy;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::utilities::write_private_file_atomic;
+
+static VAULT_FILE_NAME: &'static str = "credential_vault.json";
+static VAULT_SECRET_FILE_NAME: &'static str = "credential_vault.key";
+const VAULT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VaultFile {
+    version: u32,
+    /// Argon2 salt, hex.
+    salt: String,
+    passphrase_protected: bool,
+    /// AES-GCM nonce, hex. A new one is made on every write.
+    nonce: String,
+    /// Encrypted json map of scope name -> secret, hex.
+    ciphertext: String,
+}
+
+struct UnlockedVault {
+    key: [u8; 32],
+    salt: Vec<u8>,
+    passphrase_protected: bool,
+    secrets: BTreeMap<String, String>,
+}
+
+pub struct VaultStatus {
+    pub path: PathBuf,
+    pub exists: bool,
+    pub passphrase_protected: bool,
+    pub unlocked: bool,
+}
+
+// The vault is decrypted once and kept in memory until the app exits.
+static UNLOCKED_VAULT: Lazy<Mutex<Option<UnlockedVault>>> = Lazy::new(|| Mutex::new(None));
+
+fn vault_file_path(dir: &Path) -> PathBuf {
+    dir.join(VAULT_FILE_NAME)
+}
+
+fn machine_id() -> String {
+    #[cfg(target_os = "linux")]
+    for path in ["/etc/machine-id", "/var/lib/dbus/machine-id"] {
+        if let Ok(id) = fs::read_to_string(path) {
+            let id = id.trim();
+            if !id.is_empty() {
+                return id.to_string();
+            }
+        }
+    }
+    whoami::fallible::hostname().unwrap_or_default()
+}
+
+fn read_or_create_install_secret(dir: &Path) -> Result<Vec<u8>, String> {
+    let secret_file_path = dir.join(VAULT_SECRET_FILE_NAME);
+    if let Ok(secret) = fs::read(&secret_file_path) {
+        if secret.len() == 32 {
+            return Ok(secret);
+        }
+        if vault_file_path(dir).exists() {
+            return Err("Credential vault secret is corrupt".to_string());
+        }
+    }
+    let mut secret = vec![0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    write_private_file_atomic(&secret_file_path, &secret)
+        .map_err(|e| format!("Failed to write credential vault secret: {}", e))?;
+    Ok(secret)
+}
+
+fn derive_key(dir: &Path, salt: &[u8], passphrase: Option<&str>) -> Result<[u8; 32], String> {
+    let mut password = read_or_create_install_secret(dir)?;
+    password.extend_from_slice(machine_id().as_bytes());
+    password.push(0);
+    password.extend_from_slice(whoami::username().as_bytes());
+    if let Some(passphrase) = passphrase {
+        password.push(0);
+        password.extend_from_slice(passphrase.as_bytes());
+    }
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(&password, salt, &mut key)
+        .map_err(|e| format!("Failed to derive credential vault key: {}", e))?;
+    Ok(key)
+}
+
+fn read_vault_file(dir: &Path) -> Result<Option<VaultFile>, String> {
+    let vault_path = vault_file_path(dir);
+    let contents = match fs::read_to_string(&vault_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read credential vault: {}", e)),
+    };
+    let vault_file: VaultFile = serde_json::from_str(&contents)
+        .map_err(|e| format!("Credential vault is corrupt: {}", e))?;
+    if vault_file.version > VAULT_VERSION {
+        return Err(format!("Credential vault version {} is newer than this app", vault_file.version));
+    }
+    Ok(Some(vault_file))
+}
+
+fn write_vault(dir: &Path, vault: &UnlockedVault) -> Result<(), String> {
+    let plaintext = serde_json::to_vec(&vault.secrets).map_err(|e| e.to_string())?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&vault.key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, plaintext.as_slice())
+        .map_err(|_| "Failed to encrypt credential vault".to_string())?;
+    let vault_file = VaultFile {
+        version: VAULT_VERSION,
+        salt: hex::encode(&vault.salt),
+        passphrase_protected: vault.passphrase_protected,
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    };
+    let json_string = serde_json::to_string(&vault_file).map_err(|e| e.to_string())?;
+    let vault_path = vault_file_path(dir);
+    write_private_file_atomic(&vault_path, json_string.as_bytes())
+        .map_err(|e| format!("Failed to write credential vault: {}", e))
+}
+
+fn open_vault(dir: &Path, passphrase: Option<&str>) -> Result<UnlockedVault, String> {
+    let Some(vault_file) = read_vault_file(dir)? else {
+        // nothing stored yet, start an empty vault
+        let mut salt = vec![0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let key = derive_key(dir, &salt, passphrase)?;
+        return Ok(UnlockedVault { key, salt, passphrase_protected: passphrase.is_some(), secrets: BTreeMap::new() });
+    };
+    if vault_file.passphrase_protected && passphrase.is_none() {
+        return Err("The credential vault is locked. Unlock it with unlock_credential_vault first.".to_string());
+    }
+    let salt = hex::decode(&vault_file.salt).map_err(|_| "Credential vault is corrupt".to_string())?;
+    let nonce = hex::decode(&vault_file.nonce).map_err(|_| "Credential vault is corrupt".to_string())?;
+    let ciphertext = hex::decode(&vault_file.ciphertext).map_err(|_| "Credential vault is corrupt".to_string())?;
+    if nonce.len() != 12 {
+        return Err("Credential vault is corrupt".to_string());
+    }
+    let key = derive_key(dir, &salt, passphrase.filter(|_| vault_file.passphrase_protected))?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| "Failed to open the credential vault. Wrong passphrase, or it was made on another machine or by another user.".to_string())?;
+    let secrets = serde_json::from_slice(&plaintext).map_err(|_| "Credential vault is corrupt".to_string())?;
+    Ok(UnlockedVault { key, salt, passphrase_protected: vault_file.passphrase_protected, secrets })
+}
+
+// Runs `f` on the unlocked vault, opening it first if it isn't protected by a passphrase.
+fn with_vault<T, F: FnOnce(&mut UnlockedVault) -> Result<T, String>>(dir: &Path, f: F) -> Result<T, String> {
+    let mut unlocked = UNLOCKED_VAULT.lock().unwrap();
+    if unlocked.is_none() {
+        *unlocked = Some(open_vault(dir, None)?);
+    }
+    f(unlocked.as_mut().unwrap())
+}
+
+/// Opens a passphrase protected vault. For a new vault, this sets the passphrase.
+pub fn unlock(dir: &Path, passphrase: &str) -> Result<(), String> {
+    let vault = open_vault(dir, Some(passphrase))?;
+    *UNLOCKED_VAULT.lock().unwrap() = Some(vault);
+    Ok(())
+}
+
+/// Re-encrypts the vault with a new passphrase, or with none. The vault must be unlocked.
+pub fn set_passphrase(dir: &Path, passphrase: Option<&str>) -> Result<(), String> {
+    with_vault(dir, |vault| {
+        let mut salt = vec![0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        vault.key = derive_key(dir, &salt, passphrase)?;
+        vault.salt = salt;
+        vault.passphrase_protected = passphrase.is_some();
+        write_vault(dir, vault)
+    })
+}
+
+pub fn set(dir: &Path, scope_name: &str, secret_val: &str) -> Result<(), String> {
+    with_vault(dir, |vault| {
+        vault.secrets.insert(scope_name.to_string(), secret_val.to_string());
+        write_vault(dir, vault)
+    })
+}
+
+pub fn get(dir: &Path, scope_name: &str) -> Result<Option<String>, String> {
+    with_vault(dir, |vault| Ok(vault.secrets.get(scope_name).cloned()))
+}
+
+/// Returns false if there was no secret for the scope.
+pub fn delete(dir: &Path, scope_name: &str) -> Result<bool, String> {
+    with_vault(dir, |vault| {
+        if vault.secrets.remove(scope_name).is_none() {
+            return Ok(false);
+        }
+        write_vault(dir, vault)?;
+        Ok(true)
+    })
+}
+
+pub fn status(dir: &Path) -> VaultStatus {
+    let vault_file = read_vault_file(dir).ok().flatten();
+    VaultStatus {
+        path: vault_file_path(dir),
+        exists: vault_file.is_some(),
+        passphrase_protected: vault_file.map_or(false, |vault_file| vault_file.passphrase_protected),
+        unlocked: UNLOCKED_VAULT.lock().unwrap().is_some(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::test_dir;
+
+    fn forget_unlocked_vault() {
+        *UNLOCKED_VAULT.lock().unwrap() = None;
+    }
+
+    // One test, as the unlocked vault is process wide. Needs no keyring or D-Bus session.
+    #[test]
+    fn vault_backend() {
+        let dir = test_dir("credential-vault-backend");
+        forget_unlocked_vault();
+        assert!(!status(&dir).exists);
+
+        set(&dir, "github", "token-1").unwrap();
+        set(&dir, "gitlab", "token-2").unwrap();
+        assert_eq!(get(&dir, "github").unwrap().as_deref(), Some("token-1"));
+        assert!(delete(&dir, "gitlab").unwrap());
+        assert!(!delete(&dir, "gitlab").unwrap());
+
+        // nothing in plaintext on disk, and reopening from disk gives the same secrets
+        let contents = fs::read_to_string(vault_file_path(&dir)).unwrap();
+        assert!(!contents.contains("token-1"));
+        forget_unlocked_vault();
+        assert_eq!(get(&dir, "github").unwrap().as_deref(), Some("token-1"));
+        assert_eq!(get(&dir, "gitlab").unwrap(), None);
+
+        #[cfg(unix)]{
+            use std::os::unix::fs::PermissionsExt;
+            for file_name in [VAULT_FILE_NAME, VAULT_SECRET_FILE_NAME] {
+                let mode = fs::metadata(dir.join(file_name)).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600, "{}", file_name);
+            }
+        }
+
+        // a passphrase locks the vault until it is unlocked with the same passphrase
+        set_passphrase(&dir, Some("hunter2")).unwrap();
+        forget_unlocked_vault();
+        assert!(status(&dir).passphrase_protected);
+        assert!(get(&dir, "github").is_err());
+        assert!(unlock(&dir, "wrong").is_err());
+        unlock(&dir, "hunter2").unwrap();
+        assert_eq!(get(&dir, "github").unwrap().as_deref(), Some("token-1"));
+
+        // the vault doesn't open without its install secret
+        set_passphrase(&dir, None).unwrap();
+        forget_unlocked_vault();
+        fs::write(dir.join(VAULT_SECRET_FILE_NAME), [0u8; 32]).unwrap();
+        assert!(get(&dir, "github").is_err());
+
+        forget_unlocked_vault();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+// Credentials stored in the system keyring, one entry per scope under the service
+// "phcode_<scope name>". The keyring can't be enumerated, so an index of the scopes with their
+// timestamps and optional expiry is kept next to it in app_local_data_dir. The index holds no
+// secrets. Credentials stored before the index existed are not listed, but can still be read
+// and deleted by scope name.
+// When the keyring can't be used, for example on Linux without a Secret Service daemon, the
+// encrypted file vault in credential_vault.rs is used instead. Setting the env var
+// PHCODE_CREDENTIAL_BACKEND to "vault" forces the vault, to test it on machines with a keyring.
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use keyring::Entry;
+use crate::boot_config::APP_CONSTANTS;
+use crate::utilities::{read_json_file, unix_time_millis, write_file_atomic};
+use crate::credential_vault;
+
+const PHOENIX_CRED_PREFIX: &str = "phcode_";
+static CREDENTIAL_INDEX_FILE_NAME: &'static str = "credential_index.json";
+const BACKEND_ENV_VAR: &str = "PHCODE_CREDENTIAL_BACKEND";
+// looked up to find out if the keyring works, never written
+const KEYRING_PROBE_SCOPE: &str = "__backend_probe__";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Backend {
+    Keyring,
+    Vault,
+}
+
+struct BackendChoice {
+    backend: Backend,
+    /// Why the keyring isn't used, if it isn't.
+    keyring_error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BackendInfo {
+    pub backend: Backend,
+    pub keyring_error: Option<String>,
+    pub vault_path: Option<String>,
+    pub vault_exists: bool,
+    pub vault_passphrase_protected: bool,
+    pub vault_unlocked: bool,
+}
+
+static BACKEND: OnceCell<BackendChoice> = OnceCell::new();
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialInfo {
+    pub scope_name: String,
+    /// Unix time in milliseconds.
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// The credential is deleted once this unix time in milliseconds has passed.
+    pub expires_at: Option<u64>,
+}
+
+impl CredentialInfo {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default)]
+struct CredentialIndex {
+    scopes: BTreeMap<String, CredentialInfo>,
+}
+
+// Serializes read-modify-write cycles of the index file.
+static INDEX_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+fn get_username() -> String {
+    // Ensure a fallback username in case retrieval fails
+    match whoami::username().as_str() {
+        "" => "unknown_user".to_string(), // Fallback if username is empty
+        username => username.to_string(), // Otherwise, use the retrieved username
+    }
+}
+
+fn choose_backend() -> BackendChoice {
+    if std::env::var(BACKEND_ENV_VAR).map_or(false, |backend| backend == "vault") {
+        return BackendChoice { backend: Backend::Vault, keyring_error: Some(format!("{} is set to vault", BACKEND_ENV_VAR)) };
+    }
+    let probe = keyring_entry(KEYRING_PROBE_SCOPE).and_then(|entry| match entry.get_password() {
+        Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e @ keyring::Error::PlatformFailure(_)) | Err(e @ keyring::Error::NoStorageAccess(_)) => Err(e.to_string()),
+        // the keyring answered, just not with something we expected
+        Err(_) => Ok(()),
+    });
+    match probe {
+        Ok(()) => BackendChoice { backend: Backend::Keyring, keyring_error: None },
+        Err(e) => {
+            eprintln!("OS keyring unavailable, storing credentials in the encrypted vault: {}", e);
+            BackendChoice { backend: Backend::Vault, keyring_error: Some(e) }
+        }
+    }
+}
+
+fn backend() -> Backend {
+    BACKEND.get_or_init(choose_backend).backend
+}
+
+fn vault_dir() -> Result<PathBuf, String> {
+    APP_CONSTANTS.get().map(|app_constants| app_constants.app_local_data_dir.clone())
+        .ok_or("App constants not initialized".to_string())
+}
+
+fn keyring_entry(scope_name: &str) -> Result<Entry, String> {
+    let service = format!("{}{}", PHOENIX_CRED_PREFIX, scope_name); // Unique service name per scope
+    Entry::new(&service, &get_username()).map_err(|e| e.to_string())
+}
+
+fn get_index_file_path() -> Option<PathBuf> {
+    let app_constants = APP_CONSTANTS.get()?;
+    let mut index_file_path = app_constants.app_local_data_dir.clone();
+    index_file_path.push(CREDENTIAL_INDEX_FILE_NAME);
+    Some(index_file_path)
+}
+
+fn read_index() -> CredentialIndex {
+    get_index_file_path()
+        .and_then(|path| read_json_file(&path))
+        .and_then(|value| serde_json::from_value(value).ok())
+        .unwrap_or_default()
+}
+
+fn write_index(index: &CredentialIndex) -> Result<(), String> {
+    let index_file_path = get_index_file_path().ok_or("App constants not initialized")?;
+    let json_string = serde_json::to_string(index).map_err(|e| e.to_string())?;
+    write_file_atomic(&index_file_path, json_string.as_bytes()).map_err(|e| e.to_string())
+}
+
+// Index failures are logged and don't fail the credential operation, the keyring is the source
+// of truth for the secrets.
+fn update_index<F: FnOnce(&mut CredentialIndex)>(update: F) {
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = read_index();
+    update(&mut index);
+    if let Err(e) = write_index(&index) {
+        eprintln!("Failed to write credential index: {}", e);
+    }
+}
+
+fn backend_set(scope_name: &str, secret_val: &str) -> Result<(), String> {
+    match backend() {
+        Backend::Keyring => keyring_entry(scope_name)?.set_password(secret_val).map_err(|e| e.to_string()),
+        Backend::Vault => credential_vault::set(&vault_dir()?, scope_name, secret_val),
+    }
+}
+
+fn backend_get(scope_name: &str) -> Result<Option<String>, String> {
+    match backend() {
+        Backend::Keyring => match keyring_entry(scope_name)?.get_password() {
+            Ok(data) => Ok(Some(data)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(format!("Failed to retrieve credential: {}", e.to_string())),
+        },
+        Backend::Vault => credential_vault::get(&vault_dir()?, scope_name),
+    }
+}
+
+// Returns false if there was nothing to delete.
+fn backend_delete(scope_name: &str) -> Result<bool, String> {
+    match backend() {
+        Backend::Keyring => match keyring_entry(scope_name)?.delete_password() {
+            Ok(()) => Ok(true),
+            Err(keyring::Error::NoEntry) => Ok(false),
+            Err(e) => Err(e.to_string()),
+        },
+        Backend::Vault => credential_vault::delete(&vault_dir()?, scope_name),
+    }
+}
+
+/// Stores `secret_val` for the scope. It is deleted after `expires_at`, a unix time in
+/// milliseconds, if given.
+pub fn store(scope_name: &str, secret_val: &str, expires_at: Option<u64>) -> Result<(), String> {
+    backend_set(scope_name, secret_val)?;
+    let now = unix_time_millis();
+    update_index(|index| {
+        let info = index.scopes.entry(scope_name.to_string()).or_insert_with(|| CredentialInfo {
+            scope_name: scope_name.to_string(),
+            created_at: now,
+            updated_at: now,
+            expires_at: None,
+        });
+        info.updated_at = now;
+        info.expires_at = expires_at;
+    });
+    Ok(())
+}
+
+/// Returns None if there is no credential for the scope or if it expired, in which case it is
+/// deleted.
+pub fn get(scope_name: &str) -> Result<Option<String>, String> {
+    let expired = {
+        let _guard = INDEX_LOCK.lock().unwrap();
+        read_index().scopes.get(scope_name).map_or(false, |info| info.is_expired(unix_time_millis()))
+    };
+    if expired {
+        delete(scope_name)?;
+        return Ok(None);
+    }
+    backend_get(scope_name)
+}
+
+/// Returns false if there was no credential for the scope.
+pub fn delete(scope_name: &str) -> Result<bool, String> {
+    let existed = backend_delete(scope_name)?;
+    update_index(|index| {
+        index.scopes.remove(scope_name);
+    });
+    Ok(existed)
+}
+
+/// Lists the scopes in the index that haven't expired.
+pub fn list_scopes() -> Vec<CredentialInfo> {
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let now = unix_time_millis();
+    read_index().scopes.into_values().filter(|info| !info.is_expired(now)).collect()
+}
+
+/// Deletes every indexed credential, for sign out. Returns the number of credentials deleted.
+pub fn delete_all() -> Result<usize, String> {
+    delete_matching(|_| true)
+}
+
+/// Deletes the expired credentials. Called at startup.
+pub fn purge_expired() -> Result<usize, String> {
+    let now = unix_time_millis();
+    delete_matching(|info| info.is_expired(now))
+}
+
+// Scopes whose keyring entry couldn't be deleted stay in the index so that they can be retried.
+fn delete_matching<F: Fn(&CredentialInfo) -> bool>(matches: F) -> Result<usize, String> {
+    let _guard = INDEX_LOCK.lock().unwrap();
+    let mut index = read_index();
+    let mut deleted = 0;
+    let mut errors = Vec::new();
+    index.scopes.retain(|scope_name, info| {
+        if !matches(info) {
+            return true;
+        }
+        match backend_delete(scope_name) {
+            Ok(_) => {
+                deleted += 1;
+                false
+            }
+            Err(e) => {
+                errors.push(format!("{}: {}", scope_name, e));
+                true
+            }
+        }
+    });
+    write_index(&index)?;
+    if !errors.is_empty() {
+        return Err(format!("Failed to delete credentials {}", errors.join(", ")));
+    }
+    Ok(deleted)
+}
+
+/// Which backend stores the credentials, and the vault state if it is the vault.
+pub fn backend_info() -> BackendInfo {
+    let choice = BACKEND.get_or_init(choose_backend);
+    let vault_status = vault_dir().ok().map(|dir| credential_vault::status(&dir));
+    BackendInfo {
+        backend: choice.backend,
+        keyring_error: choice.keyring_error.clone(),
+        vault_path: vault_status.as_ref().map(|status| status.path.display().to_string()),
+        vault_exists: vault_status.as_ref().map_or(false, |status| status.exists),
+        vault_passphrase_protected: vault_status.as_ref().map_or(false, |status| status.passphrase_protected),
+        vault_unlocked: vault_status.as_ref().map_or(false, |status| status.unlocked),
+    }
+}
+
+pub fn unlock_vault(passphrase: &str) -> Result<(), String> {
+    credential_vault::unlock(&vault_dir()?, passphrase)
+}
+
+pub fn set_vault_passphrase(passphrase: Option<&str>) -> Result<(), String> {
+    credential_vault::set_passphrase(&vault_dir()?, passphrase)
+}
@@ -0,0 +1,320 @@
+// Pure window placement logic used to restore saved window geometry onto the monitors that are
+// connected right now. Everything here is in physical pixels unless stated otherwise, and has no
+// tauri dependency so that it can be reasoned about over synthetic monitor layouts.
+
+/// Height of the draggable title bar strip, in logical pixels.
+const TITLE_BAR_HEIGHT: f64 = 30.0;
+/// How much of the title bar must land on a monitor for the user to be able to drag the window,
+/// in logical pixels.
+const MIN_VISIBLE_TITLE_BAR_WIDTH: f64 = 100.0;
+const MIN_VISIBLE_TITLE_BAR_HEIGHT: f64 = 20.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    fn right(&self) -> i64 {
+        self.x as i64 + self.width as i64
+    }
+
+    fn bottom(&self) -> i64 {
+        self.y as i64 + self.height as i64
+    }
+
+    /// Width and height of the overlap of the two rects, 0 by 0 if they do not overlap.
+    fn intersection_size(&self, other: &Rect) -> (u64, u64) {
+        let overlap_x = self.right().min(other.right()) - (self.x as i64).max(other.x as i64);
+        let overlap_y = self.bottom().min(other.bottom()) - (self.y as i64).max(other.y as i64);
+        if overlap_x <= 0 || overlap_y <= 0 {
+            return (0, 0);
+        }
+        (overlap_x as u64, overlap_y as u64)
+    }
+
+    fn intersection_area(&self, other: &Rect) -> u64 {
+        let (width, height) = self.intersection_size(other);
+        width * height
+    }
+
+    /// Squared distance from a point to the closest point of this rect, 0 if inside.
+    fn distance_squared_to(&self, x: i64, y: i64) -> i64 {
+        let dx = (self.x as i64 - x).max(0).max(x - self.right());
+        let dy = (self.y as i64 - y).max(0).max(y - self.bottom());
+        dx * dx + dy * dy
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorInfo {
+    pub name: Option<String>,
+    pub bounds: Rect,
+    pub scale_factor: f64,
+}
+
+/// Where a window was and which monitor it was on when its state was saved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedGeometry {
+    pub rect: Rect,
+    pub monitor_name: Option<String>,
+    /// Top left corner of the monitor, used to tell monitors apart when they have no name.
+    pub monitor_position: Option<(i32, i32)>,
+    /// 0 when unknown, e.g. for configs saved before the scale factor was recorded.
+    pub monitor_scale_factor: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    /// Move and resize the window to this rect.
+    Restore(Rect),
+    /// The saved size does not fit the target monitor. Move the window onto this monitor and
+    /// maximize it there.
+    Maximize(Rect),
+}
+
+pub fn logical_to_physical(logical: f64, scale_factor: f64) -> u32 {
+    (logical * scale_factor).round().max(0.0) as u32
+}
+
+pub fn physical_to_logical(physical: u32, scale_factor: f64) -> f64 {
+    if scale_factor <= 0.0 {
+        return physical as f64;
+    }
+    physical as f64 / scale_factor
+}
+
+/// True if enough of the window's title bar lands on the monitor for the user to grab it.
+pub fn is_title_bar_reachable(window: &Rect, monitor: &MonitorInfo) -> bool {
+    let scale_factor = if monitor.scale_factor > 0.0 { monitor.scale_factor } else { 1.0 };
+    let title_bar = Rect {
+        x: window.x,
+        y: window.y,
+        width: window.width,
+        height: window.height.min(logical_to_physical(TITLE_BAR_HEIGHT, scale_factor)),
+    };
+    let (visible_width, visible_height) = title_bar.intersection_size(&monitor.bounds);
+    // a window smaller than the minimum only needs its whole title bar on screen
+    let min_width = logical_to_physical(MIN_VISIBLE_TITLE_BAR_WIDTH, scale_factor).min(title_bar.width);
+    let min_height = logical_to_physical(MIN_VISIBLE_TITLE_BAR_HEIGHT, scale_factor).min(title_bar.height);
+    visible_width > 0 && visible_height > 0
+        && visible_width >= min_width as u64 && visible_height >= min_height as u64
+}
+
+/// Picks the monitor the window should be restored onto: the monitor it was saved on if the
+/// title bar is still reachable there, then any monitor where the title bar is reachable, and
+/// finally the monitor nearest to the window's title bar.
+pub fn target_monitor<'a>(saved: &SavedGeometry, monitors: &'a [MonitorInfo]) -> Option<&'a MonitorInfo> {
+    let is_saved_monitor = |monitor: &MonitorInfo| match (&saved.monitor_name, saved.monitor_position) {
+        (Some(_), _) => monitor.name == saved.monitor_name,
+        (None, Some((x, y))) => monitor.bounds.x == x && monitor.bounds.y == y,
+        (None, None) => false,
+    };
+    let same_monitor = monitors.iter().find(|monitor| {
+        is_saved_monitor(monitor) && is_title_bar_reachable(&saved.rect, monitor)
+    });
+    if same_monitor.is_some() {
+        return same_monitor;
+    }
+    let reachable = monitors.iter()
+        .filter(|monitor| is_title_bar_reachable(&saved.rect, monitor))
+        .max_by_key(|monitor| saved.rect.intersection_area(&monitor.bounds));
+    if reachable.is_some() {
+        return reachable;
+    }
+    let anchor_x = saved.rect.x as i64 + saved.rect.width as i64 / 2;
+    let anchor_y = saved.rect.y as i64;
+    monitors.iter().min_by_key(|monitor| monitor.bounds.distance_squared_to(anchor_x, anchor_y))
+}
+
+/// Computes where to put a window with the given saved geometry on the current monitor layout.
+/// The window keeps its logical size across monitors with different scale factors. Returns
+/// `None` when there are no monitors to place the window on.
+pub fn place_window(saved: &SavedGeometry, monitors: &[MonitorInfo]) -> Option<Placement> {
+    let monitor = target_monitor(saved, monitors)?;
+    let bounds = monitor.bounds;
+
+    let (width, height) = if saved.monitor_scale_factor > 0.0 && monitor.scale_factor > 0.0 {
+        let saved_scale = saved.monitor_scale_factor;
+        (
+            logical_to_physical(physical_to_logical(saved.rect.width, saved_scale), monitor.scale_factor),
+            logical_to_physical(physical_to_logical(saved.rect.height, saved_scale), monitor.scale_factor),
+        )
+    } else {
+        (saved.rect.width, saved.rect.height)
+    };
+
+    // Maximizing lets the OS fit the window within the usable work area, which excludes the
+    // taskbar/dock that the monitor bounds include.
+    if width > bounds.width || height > bounds.height {
+        return Some(Placement::Maximize(bounds));
+    }
+
+    let resized = Rect { x: saved.rect.x, y: saved.rect.y, width, height };
+    if is_title_bar_reachable(&resized, monitor) {
+        return Some(Placement::Restore(resized));
+    }
+
+    // center on the target monitor
+    Some(Placement::Restore(Rect {
+        x: bounds.x + ((bounds.width - width) / 2) as i32,
+        y: bounds.y + ((bounds.height - height) / 2) as i32,
+        width,
+        height,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(name: &str, x: i32, y: i32, width: u32, height: u32, scale_factor: f64) -> MonitorInfo {
+        MonitorInfo {
+            name: Some(name.to_string()),
+            bounds: Rect { x, y, width, height },
+            scale_factor,
+        }
+    }
+
+    fn saved(rect: Rect, monitor_name: Option<&str>, monitor_scale_factor: f64) -> SavedGeometry {
+        SavedGeometry {
+            rect,
+            monitor_name: monitor_name.map(str::to_string),
+            monitor_position: None,
+            monitor_scale_factor,
+        }
+    }
+
+    fn rect(x: i32, y: i32, width: u32, height: u32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    #[test]
+    fn restores_window_where_it_was() {
+        let monitors = [monitor("primary", 0, 0, 1920, 1080, 1.0)];
+        let geometry = saved(rect(100, 50, 800, 600), Some("primary"), 1.0);
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Restore(rect(100, 50, 800, 600))));
+    }
+
+    #[test]
+    fn no_monitors() {
+        let geometry = saved(rect(100, 50, 800, 600), Some("primary"), 1.0);
+        assert_eq!(target_monitor(&geometry, &[]), None);
+        assert_eq!(place_window(&geometry, &[]), None);
+    }
+
+    #[test]
+    fn unplugged_monitor_centers_on_remaining_one() {
+        let monitors = [monitor("primary", 0, 0, 1920, 1080, 1.0)];
+        // was on a monitor to the right of the primary one that is gone now
+        let geometry = saved(rect(2100, 100, 800, 600), Some("external"), 1.0);
+        assert_eq!(target_monitor(&geometry, &monitors).unwrap().name.as_deref(), Some("primary"));
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Restore(rect(560, 240, 800, 600))));
+    }
+
+    #[test]
+    fn prefers_saved_monitor_when_window_spans_two() {
+        let monitors = [
+            monitor("left", 0, 0, 1920, 1080, 1.0),
+            monitor("right", 1920, 0, 1920, 1080, 1.0),
+        ];
+        // mostly on the left monitor, but saved as being on the right one
+        let geometry = saved(rect(1000, 100, 1200, 600), Some("right"), 1.0);
+        assert_eq!(target_monitor(&geometry, &monitors).unwrap().name.as_deref(), Some("right"));
+        let geometry = saved(rect(1000, 100, 1200, 600), None, 1.0);
+        assert_eq!(target_monitor(&geometry, &monitors).unwrap().name.as_deref(), Some("left"));
+    }
+
+    #[test]
+    fn unnamed_monitor_is_matched_by_position() {
+        let mut monitors = [
+            monitor("", 0, 0, 1920, 1080, 1.0),
+            monitor("", 1920, 0, 1920, 1080, 1.0),
+        ];
+        for monitor in monitors.iter_mut() {
+            monitor.name = None;
+        }
+        let geometry = SavedGeometry {
+            rect: rect(1000, 100, 1200, 600),
+            monitor_name: None,
+            monitor_position: Some((1920, 0)),
+            monitor_scale_factor: 1.0,
+        };
+        assert_eq!(target_monitor(&geometry, &monitors).unwrap().bounds.x, 1920);
+    }
+
+    #[test]
+    fn negative_origins() {
+        let monitors = [
+            monitor("primary", 0, 0, 1920, 1080, 1.0),
+            monitor("left", -2560, -360, 2560, 1440, 1.0),
+        ];
+        let geometry = saved(rect(-2400, -300, 1000, 700), Some("left"), 1.0);
+        assert_eq!(target_monitor(&geometry, &monitors).unwrap().name.as_deref(), Some("left"));
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Restore(rect(-2400, -300, 1000, 700))));
+
+        // left monitor unplugged: nearest is the primary one
+        let geometry = saved(rect(-2400, -300, 1000, 700), Some("left"), 1.0);
+        assert_eq!(place_window(&geometry, &monitors[..1]), Some(Placement::Restore(rect(460, 190, 1000, 700))));
+    }
+
+    #[test]
+    fn keeps_logical_size_across_scale_factor_change() {
+        // saved at 100% on a 1080p monitor, which is now a 4k monitor at 200%
+        let monitors = [monitor("primary", 0, 0, 3840, 2160, 2.0)];
+        let geometry = saved(rect(100, 100, 1000, 800), Some("primary"), 1.0);
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Restore(rect(100, 100, 2000, 1600))));
+
+        // unknown saved scale factor keeps the physical size
+        let geometry = saved(rect(100, 100, 1000, 800), Some("primary"), 0.0);
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Restore(rect(100, 100, 1000, 800))));
+    }
+
+    #[test]
+    fn scale_factor_change_can_overflow_monitor() {
+        let monitors = [monitor("primary", 0, 0, 2560, 1440, 2.0)];
+        let geometry = saved(rect(0, 0, 1600, 900), Some("primary"), 1.0);
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Maximize(rect(0, 0, 2560, 1440))));
+    }
+
+    #[test]
+    fn window_larger_than_monitor_is_maximized() {
+        let monitors = [
+            monitor("primary", 0, 0, 1920, 1080, 1.0),
+            monitor("small", 1920, 0, 1280, 720, 1.0),
+        ];
+        let geometry = saved(rect(1950, 10, 1600, 900), Some("small"), 1.0);
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Maximize(rect(1920, 0, 1280, 720))));
+    }
+
+    #[test]
+    fn title_bar_reachability() {
+        let primary = monitor("primary", 0, 0, 1920, 1080, 1.0);
+        assert!(is_title_bar_reachable(&rect(100, 100, 800, 600), &primary));
+        // title bar above the top of the screen, even though the rest of the window is visible
+        assert!(!is_title_bar_reachable(&rect(100, -100, 800, 600), &primary));
+        // only 50 pixels of the title bar left on screen
+        assert!(!is_title_bar_reachable(&rect(1870, 100, 800, 600), &primary));
+        assert!(is_title_bar_reachable(&rect(1770, 100, 800, 600), &primary));
+        // only 10 pixels of the title bar height on screen
+        assert!(!is_title_bar_reachable(&rect(100, 1070, 800, 600), &primary));
+        // entirely off screen
+        assert!(!is_title_bar_reachable(&rect(5000, 5000, 800, 600), &primary));
+        // a tiny window only needs its whole title bar on screen
+        assert!(is_title_bar_reachable(&rect(10, 10, 60, 15), &primary));
+        // the minimum visible width scales with the monitor
+        let hidpi = monitor("hidpi", 0, 0, 3840, 2160, 2.0);
+        assert!(!is_title_bar_reachable(&rect(3690, 100, 800, 600), &hidpi));
+        assert!(is_title_bar_reachable(&rect(3640, 100, 800, 600), &hidpi));
+    }
+
+    #[test]
+    fn off_screen_title_bar_is_centered() {
+        let monitors = [monitor("primary", 0, 0, 1920, 1080, 1.0)];
+        let geometry = saved(rect(100, -400, 800, 600), Some("primary"), 1.0);
+        assert_eq!(place_window(&geometry, &monitors), Some(Placement::Restore(rect(560, 240, 800, 600))));
+    }
+}
@@ -0,0 +1,188 @@
+// Crash report sink that posts reports as Sentry envelopes to a configurable endpoint, for self
+// hosted Sentry servers or any collector that accepts the format. See crash_report.rs.
+// https://develop.sentry.dev/sdk/data-model/envelopes/
+
+use chrono::prelude::*;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use crate::breadcrumbs::{Breadcrumb, BreadcrumbType};
+use crate::crash_report::{CrashReport, CrashReportSink, JsonFrame, ThreadInfo};
+use crate::crash_spool::UploadTarget;
+
+const SENTRY_PROTOCOL_VERSION: u32 = 7;
+const SENTRY_CLIENT_NAME: &str = "phoenix-code-desktop";
+
+pub struct HttpSink {
+    endpoint: String,
+    /// Sentry public key, sent in the X-Sentry-Auth header if set.
+    api_key: Option<String>,
+}
+
+impl HttpSink {
+    pub fn new(endpoint: String, api_key: Option<String>) -> Self {
+        HttpSink { endpoint, api_key }
+    }
+
+    fn construct_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/x-sentry-envelope"));
+        if let Some(api_key) = &self.api_key {
+            let auth = format!("Sentry sentry_version={}, sentry_key={}, sentry_client={}/{}",
+                SENTRY_PROTOCOL_VERSION, api_key, SENTRY_CLIENT_NAME, env!("CARGO_PKG_VERSION"));
+            if let Ok(auth) = HeaderValue::from_str(&auth) {
+                headers.insert("X-Sentry-Auth", auth);
+            }
+        }
+        headers
+    }
+}
+
+/// The id of the report, so that retries of a report are the same Sentry event. Reports spooled
+/// without an id get one derived from their contents.
+fn event_id(report: &CrashReport) -> String {
+    if !report.id.is_empty() {
+        return report.id.clone();
+    }
+    let digest = Sha256::digest(serde_json::to_vec(report).unwrap_or_default());
+    hex::encode(&digest[..16])
+}
+
+fn sentry_level(report: &CrashReport) -> &str {
+    match report.severity.as_str() {
+        "error" if report.unhandled => "fatal",
+        "warning" => "warning",
+        "info" => "info",
+        _ => "error",
+    }
+}
+
+// Sentry wants the frames outermost first.
+fn sentry_frames(stacktrace: &[JsonFrame]) -> Vec<Value> {
+    stacktrace.iter().rev().map(|frame| json!({
+        "filename": frame.file,
+        "function": frame.method,
+        "lineno": frame.line_number,
+        "colno": frame.column_number,
+        "in_app": frame.in_project,
+    })).collect()
+}
+
+fn sentry_breadcrumb(breadcrumb: &Breadcrumb) -> Value {
+    let breadcrumb_type = match breadcrumb.breadcrumb_type {
+        BreadcrumbType::Navigation => "navigation",
+        BreadcrumbType::Request => "http",
+        BreadcrumbType::Error => "error",
+        BreadcrumbType::User => "user",
+        _ => "default",
+    };
+    json!({
+        "timestamp": breadcrumb.timestamp,
+        "type": breadcrumb_type,
+        "category": breadcrumb.breadcrumb_type,
+        "message": breadcrumb.name,
+        "data": breadcrumb.meta_data,
+    })
+}
+
+fn sentry_thread(thread: &ThreadInfo) -> Value {
+    let mut sentry_thread = json!({
+        "id": thread.id,
+        "name": thread.name,
+        "crashed": thread.error_reporting_thread,
+        "current": thread.error_reporting_thread,
+    });
+    if !thread.stacktrace.is_empty() {
+        sentry_thread["stacktrace"] = json!({ "frames": sentry_frames(&thread.stacktrace) });
+    }
+    sentry_thread
+}
+
+fn sentry_event(event_id: &str, report: &CrashReport) -> Value {
+    json!({
+        "event_id": event_id,
+        "timestamp": report.time,
+        "platform": "native",
+        "level": sentry_level(report),
+        "release": report.app_version,
+        "environment": report.release_stage,
+        "fingerprint": [report.grouping_hash],
+        "exception": {
+            "values": [{
+                "type": report.error_class,
+                "value": report.message,
+                "mechanism": { "type": report.error_type, "handled": !report.unhandled },
+                "stacktrace": { "frames": sentry_frames(&report.stacktrace) },
+            }],
+        },
+        "contexts": {
+            "os": { "name": report.os_name, "version": report.os_version },
+            "device": { "arch": report.arch },
+        },
+        "breadcrumbs": { "values": report.breadcrumbs.iter().map(sentry_breadcrumb).collect::<Vec<_>>() },
+        "threads": { "values": report.threads.iter().map(sentry_thread).collect::<Vec<_>>() },
+        "extra": report.meta_data,
+    })
+}
+
+impl CrashReportSink for HttpSink {
+    fn upload_target(&self) -> Option<UploadTarget> {
+        Some(UploadTarget { url: self.endpoint.clone(), headers: self.construct_headers() })
+    }
+
+    fn encode(&self, report: &CrashReport) -> Result<Vec<u8>, String> {
+        let event_id = event_id(report);
+        let event = serde_json::to_vec(&sentry_event(&event_id, report)).map_err(|e| e.to_string())?;
+        let envelope_header = json!({
+            "event_id": event_id,
+            "sent_at": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        });
+        let item_header = json!({ "type": "event", "length": event.len() });
+
+        let mut envelope = Vec::with_capacity(event.len() + 256);
+        for header in [envelope_header, item_header] {
+            envelope.extend_from_slice(header.to_string().as_bytes());
+            envelope.push(b'\n');
+        }
+        envelope.extend_from_slice(&event);
+        envelope.push(b'\n');
+        Ok(envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spooled_report(id: &str) -> CrashReport {
+        serde_json::from_value(json!({
+            "errorClass": "Rust_Panic", "message": "boom", "stacktrace": [], "errorType": "rust",
+            "context": "src/main.rs:1", "groupingHash": "abc", "unhandled": true, "severity": "error",
+            "time": "2024-01-01T00:00:00.000Z", "appVersion": "1.0.0", "releaseStage": "production",
+            "osName": "linux", "osVersion": "6", "arch": "x86_64", "id": id,
+        })).unwrap()
+    }
+
+    fn envelope_event_id(envelope: &[u8]) -> String {
+        let header_line = envelope.split(|byte| *byte == b'\n').next().unwrap();
+        let header: Value = serde_json::from_slice(header_line).unwrap();
+        header["event_id"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn retries_send_the_same_event_id() {
+        let sink = HttpSink::new("https://collector.invalid/".to_string(), None);
+        let report = spooled_report("0123456789abcdef0123456789abcdef");
+        let first = sink.encode(&report).unwrap();
+        let second = sink.encode(&report).unwrap();
+        assert_eq!(envelope_event_id(&first), "0123456789abcdef0123456789abcdef");
+        assert_eq!(envelope_event_id(&second), "0123456789abcdef0123456789abcdef");
+
+        // reports spooled before reports had ids
+        let old_report = spooled_report("");
+        let event_id = envelope_event_id(&sink.encode(&old_report).unwrap());
+        assert_eq!(event_id.len(), 32);
+        assert_eq!(envelope_event_id(&sink.encode(&old_report).unwrap()), event_id);
+        assert_ne!(event_id, envelope_event_id(&sink.encode(&CrashReport { message: "other".to_string(), ..old_report }).unwrap()));
+    }
+}
@@ -1,88 +1,203 @@
-use crate::utilities::ensure_dir_exists;
-use crate::boot_config::{read_boot_config, BootConfig};
+use crate::utilities::{ensure_dir_exists, unix_time_millis};
+use crate::boot_config::{read_boot_config, write_window_state, BootConfig, WindowState, MAIN_WINDOW_LABEL};
 use crate::boot_config::APP_CONSTANTS;
 use crate::boot_config::AppConstants;
+use crate::storage::{start_expiry_task, Storage};
+use crate::ipc_security::{init_origin_policy, OriginPolicy};
+use crate::credentials;
+use crate::crash_report;
+use crate::geometry::{place_window, target_monitor, MonitorInfo, Placement, Rect, SavedGeometry};
 use tauri::Manager;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
-/// Returns true if the point (x, y) falls within any of the available monitors.
-fn is_position_on_any_monitor(x: i32, y: i32, monitors: &[tauri::Monitor]) -> bool {
-    for monitor in monitors {
-        let mon_pos = monitor.position();
-        let mon_size = monitor.size();
-        if x >= mon_pos.x
-            && x < mon_pos.x + mon_size.width as i32
-            && y >= mon_pos.y
-            && y < mon_pos.y + mon_size.height as i32
+// Labels of windows whose saved state has already been applied. Windows are restored only once
+// per window, a label is removed again when its window closes.
+static RESTORED_WINDOWS: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Helper windows that aren't user-facing, their state is neither saved nor restored.
+// "colorPickerOverlay" is color_picker::OVERLAY_WINDOW_LABEL, which only exists on linux and windows.
+static UNSAVED_WINDOW_LABELS: &[&str] = &["healthData", "fileDrop", "colorPickerOverlay"];
+
+fn is_saved_window(window_label: &str) -> bool {
+    !UNSAVED_WINDOW_LABELS.contains(&window_label)
+}
+
+// View state of a window that can't be queried back from the webview, so we track it as it is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WindowViewState {
+    pub zoom_level: Option<f64>,
+    pub devtools_open: bool,
+}
+
+pub struct WindowViewStates {
+    pub view_map: Mutex<HashMap<String, WindowViewState>>,
+}
+
+/// Applies the zoom level to the window's webview.
+pub fn set_webview_zoom(window: &tauri::Window, scale_factor: f64) {
+    let _ = window.with_webview(move |webview| {
+        #[cfg(target_os = "linux")]
         {
-            return true;
+          // see https://docs.rs/webkit2gtk/0.18.2/webkit2gtk/struct.WebView.html
+          // and https://docs.rs/webkit2gtk/0.18.2/webkit2gtk/trait.WebViewExt.html
+          use webkit2gtk::traits::WebViewExt;
+          webview.inner().set_zoom_level(scale_factor);
+        }
+
+        #[cfg(windows)]
+        unsafe {
+          // see https://docs.rs/webview2-com/0.19.1/webview2_com/Microsoft/Web/WebView2/Win32/struct.ICoreWebView2Controller.html
+          webview.controller().SetZoomFactor(scale_factor).unwrap();
         }
+
+        #[cfg(target_os = "macos")]
+        unsafe {
+          let () = msg_send![webview.inner(), setPageZoom: scale_factor];
+        }
+      });
+}
+
+/// Returns the tracked view state of a window, default if nothing was set on it yet.
+pub fn get_window_view_state(window: &tauri::Window) -> WindowViewState {
+    let view_states = window.state::<WindowViewStates>();
+    let view_map = view_states.view_map.lock().unwrap();
+    view_map.get(window.label()).copied().unwrap_or_default()
+}
+
+/// Updates the tracked view state of a window.
+pub fn update_window_view_state<F: FnOnce(&mut WindowViewState)>(window: &tauri::Window, update: F) {
+    let view_states = window.state::<WindowViewStates>();
+    let mut view_map = view_states.view_map.lock().unwrap();
+    update(view_map.entry(window.label().to_string()).or_default());
+}
+
+// Zoom and devtools are applied before the geometry so that they are in place when the
+// window is shown. Devtools are only opened if this window doesn't already have them open.
+fn restore_view_state(win: &tauri::Window, window_state: &WindowState) {
+    if let Some(zoom_level) = window_state.zoom_level {
+        set_webview_zoom(win, zoom_level);
+    }
+    if window_state.devtools_open && !get_window_view_state(win).devtools_open {
+        win.open_devtools();
+    }
+    update_window_view_state(win, |view_state| {
+        view_state.zoom_level = window_state.zoom_level;
+        view_state.devtools_open = window_state.devtools_open;
+    });
+}
+
+fn to_monitor_info(monitor: &tauri::Monitor) -> MonitorInfo {
+    MonitorInfo {
+        name: monitor.name().cloned(),
+        bounds: Rect {
+            x: monitor.position().x,
+            y: monitor.position().y,
+            width: monitor.size().width,
+            height: monitor.size().height,
+        },
+        scale_factor: monitor.scale_factor(),
+    }
+}
+
+fn maximize_on_monitor(win: &tauri::Window, monitor_bounds: &Rect) {
+    // Move onto the monitor first so that the window maximizes there and not wherever the
+    // OS put it.
+    if let Err(e) = win.set_position(tauri::Position::Physical(
+        tauri::PhysicalPosition { x: monitor_bounds.x, y: monitor_bounds.y },
+    )) {
+        eprintln!("restore_window_state: failed to move window to monitor: {}", e);
+    }
+    if let Err(e) = win.maximize() {
+        eprintln!("restore_window_state: failed to maximize window: {}", e);
     }
-    false
 }
 
-/// Restores the main window's position, size, and maximized state from boot_config.
+/// Restores a window's position, size, maximized/fullscreen state, zoom and devtools from boot_config.
 /// This is intentionally fault-tolerant: any failure is logged and silently ignored
 /// so the app always starts, even with a corrupted or missing config.
-fn restore_window_state(app: &mut tauri::App, boot_config: &BootConfig) {
-    let win = match app.get_window("main") {
-        Some(w) => w,
-        None => {
-            eprintln!("restore_window_state: could not find main window");
-            return;
-        }
+fn restore_window_state(win: &tauri::Window, boot_config: &BootConfig) {
+    if !is_saved_window(win.label()) {
+        return;
+    }
+    let window_state = match boot_config.windows.get(win.label()) {
+        Some(window_state) => window_state.clone(),
+        None if win.label() == MAIN_WINDOW_LABEL => WindowState::default(),
+        // windows opened by the app for the first time are sized by whoever created them
+        None => return,
     };
 
-    if boot_config.last_window_maximized {
-        if let Err(e) = win.maximize() {
-            eprintln!("restore_window_state: failed to maximize window: {}", e);
+    restore_view_state(win, &window_state);
+    // fullscreen goes to the monitor the window is on, so it is placed there first
+    restore_geometry(win, &window_state);
+    if window_state.fullscreen {
+        if let Err(e) = win.set_fullscreen(true) {
+            eprintln!("restore_window_state: failed to set window fullscreen: {}", e);
         }
-        return;
     }
+}
 
-    let saved_w = boot_config.last_window_width;
-    let saved_h = boot_config.last_window_height;
+/// Restores a window's position and size, or maximizes it, from its saved state.
+fn restore_geometry(win: &tauri::Window, window_state: &WindowState) {
+    let monitors: Vec<MonitorInfo> = match win.available_monitors() {
+        Ok(monitors) => monitors.iter().map(to_monitor_info).collect(),
+        Err(e) => {
+            eprintln!("restore_window_state: failed to list monitors: {}", e);
+            vec![]
+        }
+    };
+    let saved_geometry = SavedGeometry {
+        rect: Rect {
+            x: window_state.x,
+            y: window_state.y,
+            width: window_state.width,
+            height: window_state.height,
+        },
+        monitor_name: window_state.monitor.clone(),
+        monitor_position: window_state.monitor_position,
+        monitor_scale_factor: window_state.monitor_scale_factor,
+    };
 
-    if saved_w > 0 && saved_h > 0 {
-        // If the saved size exceeds the current screen, maximize instead of clamping.
-        // Clamping to raw pixels would still ignore taskbar/dock; maximizing lets the
-        // OS place the window correctly within the usable work area.
-        let fits_screen = match win.current_monitor() {
-            Ok(Some(monitor)) => {
-                let screen = monitor.size();
-                saved_w <= screen.width && saved_h <= screen.height
+    if window_state.maximized {
+        match target_monitor(&saved_geometry, &monitors) {
+            Some(monitor) => maximize_on_monitor(win, &monitor.bounds),
+            None => {
+                if let Err(e) = win.maximize() {
+                    eprintln!("restore_window_state: failed to maximize window: {}", e);
+                }
             }
-            _ => true, // can't determine screen size, assume it fits
-        };
+        }
+        return;
+    }
 
-        if !fits_screen {
-            if let Err(e) = win.maximize() {
-                eprintln!("restore_window_state: failed to maximize for oversized saved state: {}", e);
+    if window_state.width > 0 && window_state.height > 0 {
+        match place_window(&saved_geometry, &monitors) {
+            Some(Placement::Restore(rect)) => {
+                if let Err(e) = win.set_size(tauri::Size::Physical(tauri::PhysicalSize {
+                    width: rect.width,
+                    height: rect.height,
+                })) {
+                    eprintln!("restore_window_state: failed to set window size: {}", e);
+                }
+                if let Err(e) = win.set_position(tauri::Position::Physical(
+                    tauri::PhysicalPosition { x: rect.x, y: rect.y },
+                )) {
+                    eprintln!("restore_window_state: failed to set window position: {}", e);
+                }
             }
-        } else {
-            if let Err(e) = win.set_size(tauri::Size::Physical(tauri::PhysicalSize {
-                width: saved_w,
-                height: saved_h,
-            })) {
-                eprintln!("restore_window_state: failed to set window size: {}", e);
+            Some(Placement::Maximize(monitor_bounds)) => {
+                // The saved size exceeds the screen. Maximize instead of clamping, as clamping
+                // to raw pixels would still ignore the taskbar/dock.
+                maximize_on_monitor(win, &monitor_bounds);
             }
-
-            // Restore position only if the saved position is on a currently connected monitor
-            let saved_x = boot_config.last_window_x;
-            let saved_y = boot_config.last_window_y;
-            match win.available_monitors() {
-                Ok(monitors) => {
-                    if is_position_on_any_monitor(saved_x, saved_y, &monitors) {
-                        if let Err(e) = win.set_position(tauri::Position::Physical(
-                            tauri::PhysicalPosition { x: saved_x, y: saved_y },
-                        )) {
-                            eprintln!("restore_window_state: failed to set window position: {}", e);
-                        }
-                    }
-                    // else: monitor disconnected, let the OS place the window
-                }
-                Err(e) => {
-                    eprintln!("restore_window_state: failed to list monitors: {}", e);
-                    // Cannot validate position; skip position restore, size is already set
+            None => {
+                // Cannot validate position; only restore the size and let the OS place the window
+                if let Err(e) = win.set_size(tauri::Size::Physical(tauri::PhysicalSize {
+                    width: window_state.width,
+                    height: window_state.height,
+                })) {
+                    eprintln!("restore_window_state: failed to set window size: {}", e);
                 }
             }
         }
@@ -134,6 +249,113 @@ pub fn init_app(app: &mut tauri::App) {
         #[cfg(debug_assertions)]{
             println!("Bootconfig version is {}", boot_config.version);
         }
-        restore_window_state(app, &boot_config);
+        init_origin_policy(OriginPolicy::for_stage(boot_config.release_stage(), boot_config.trusted_origins.clone()));
+        let storage = app.state::<Storage>();
+        storage.set_max_bytes(boot_config.storage_max_bytes);
+        storage.enable_persistence(
+            &app_constants.app_local_data_dir, boot_config.persisted_storage_prefix.clone());
+        start_expiry_task(app.handle());
+        match credentials::purge_expired() {
+            Ok(0) => {}
+            Ok(purged) => println!("Purged {} expired credentials", purged),
+            Err(e) => eprintln!("Failed to purge expired credentials: {}", e),
+        }
+        crash_report::init(&boot_config.crash_reporting, app.handle());
+        tauri::async_runtime::spawn(crash_report::upload_spooled_reports());
+        match app.get_window(MAIN_WINDOW_LABEL) {
+            Some(win) => {
+                RESTORED_WINDOWS.lock().unwrap().insert(win.label().to_string());
+                restore_window_state(&win, &boot_config);
+            }
+            None => eprintln!("restore_window_state: could not find main window"),
+        }
+    }
+}
+
+/// Restores the saved state of a window as soon as it is created, before its page loads.
+/// Windows other than `main` are created by the js layer after boot, so this is where they get
+/// restored. A window that is already visible is hidden while it is moved so that the user
+/// never sees it jump.
+pub fn restore_window_state_on_create(win: &tauri::Window) {
+    // `main` is created before init_app has loaded the app constants, it is restored there
+    if APP_CONSTANTS.get().is_none() || !is_saved_window(win.label()) {
+        return;
+    }
+    if !RESTORED_WINDOWS.lock().unwrap().insert(win.label().to_string()) {
+        return;
+    }
+    let boot_config = read_boot_config();
+    if !boot_config.windows.contains_key(win.label()) {
+        return;
+    }
+    let visible = win.is_visible().unwrap_or(false);
+    if visible {
+        if let Err(e) = win.hide() {
+            eprintln!("restore_window_state: failed to hide window: {}", e);
+        }
+    }
+    restore_window_state(win, &boot_config);
+    if visible {
+        if let Err(e) = win.show() {
+            eprintln!("restore_window_state: failed to show window: {}", e);
+        }
+    }
+}
+
+/// Saves a window's position/size so it can be restored on next launch. Helper windows are
+/// only forgotten.
+pub fn save_window_state(window: &tauri::Window) {
+    if is_saved_window(window.label()) {
+        _save_window_state(window);
     }
+    RESTORED_WINDOWS.lock().unwrap().remove(window.label());
+    let view_states = window.state::<WindowViewStates>();
+    view_states.view_map.lock().unwrap().remove(window.label());
+}
+
+fn _save_window_state(window: &tauri::Window) {
+    let maximized = window.is_maximized().unwrap_or(false);
+    let fullscreen = window.is_fullscreen().unwrap_or(false);
+    let (x, y) = match window.outer_position() {
+        Ok(pos) => (pos.x, pos.y),
+        Err(_) => (0, 0),
+    };
+    // On Windows, set_size() sets the inner (client) area, so we must save
+    // inner_size() to avoid the window growing by the decoration size on each restart.
+    // On macOS, outer_size()/set_size() are consistent, so we keep outer_size().
+    let (width, height) = {
+        #[cfg(target_os = "windows")]
+        {
+            match window.inner_size() {
+                Ok(size) => (size.width, size.height),
+                Err(_) => (0, 0),
+            }
+        }
+        #[cfg(not(target_os = "windows"))]
+        {
+            match window.outer_size() {
+                Ok(size) => (size.width, size.height),
+                Err(_) => (0, 0),
+            }
+        }
+    };
+    let monitor = match window.current_monitor() {
+        Ok(Some(monitor)) => Some(to_monitor_info(&monitor)),
+        _ => None,
+    };
+    let view_state = get_window_view_state(window);
+    write_window_state(window.label(), WindowState {
+        x,
+        y,
+        width,
+        height,
+        maximized,
+        fullscreen,
+        monitor: monitor.as_ref().and_then(|monitor| monitor.name.clone()),
+        monitor_position: monitor.as_ref().map(|monitor| (monitor.bounds.x, monitor.bounds.y)),
+        monitor_scale_factor: monitor.as_ref().map_or(0.0, |monitor| monitor.scale_factor),
+        zoom_level: view_state.zoom_level,
+        devtools_open: view_state.devtools_open,
+        saved_at: unix_time_millis(),
+    });
 }
\ No newline at end of file
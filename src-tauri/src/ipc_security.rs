@@ -0,0 +1,266 @@
+// Origin based authorization of Tauri commands, mirroring src-electron/ipc-security.js.
+// Every command needs a trust level, and the url currently loaded in the calling window decides
+// which levels it has:
+// - the bundled app (phtauri) has all levels
+// - the trusted origins from the boot config, like the hosted app, are `TrustedOrigin`
+// - in the dev stage of the boot config, localhost URLs count as the app, as the dev server runs
+//   there
+// - anything else, including pages navigated away to, only gets `Any`
+// The check runs for every command in the invoke handler, see `authorize_invoke`. The calls made
+// by the tauri JS api itself don't go through the invoke handler and are governed by the
+// allowlist in tauri.conf.json instead.
+// Unlike the Electron checks, which look at the calling frame, this goes by the url of the
+// window's top level page: tauri v1 doesn't tell which frame an invoke came from. A command
+// invoked from an iframe gets the trust of the page around it, so trusted pages must not embed
+// frames they don't trust with the IPC bridge.
+
+use once_cell::sync::OnceCell;
+use tauri::Url;
+use crate::boot_config::{build_stage, DEV_STAGE};
+use crate::utilities::phtauri_origin;
+use crate::credential_audit::{self, audited_command, AuditOutcome};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrustLevel {
+    /// Callable from any page.
+    Any,
+    /// Callable from the app and the configured trusted origins.
+    TrustedOrigin,
+    /// Callable from the app itself only. Credentials, window trust and destructive file system
+    /// commands need this.
+    AppOrigin,
+}
+
+/// Trust level each command needs. Commands not listed here need `TrustLevel::AppOrigin`, so a
+/// newly added command is locked down until it is listed.
+pub fn required_trust_level(command: &str) -> TrustLevel {
+    match command {
+        "console_log" | "console_error" => TrustLevel::Any,
+
+        "store_credential" | "get_credential" | "delete_credential"
+        | "list_credential_scopes" | "delete_all_credentials"
+        | "credential_backend_info" | "unlock_credential_vault" | "set_credential_vault_passphrase"
+        | "get_credential_audit_log"
+        | "trust_window_aes_key" | "remove_trust_window_aes_key"
+        | "put_sealed_item" | "get_sealed_item" | "delete_sealed_item"
+        | "_rename_path" | "move_to_trash" => TrustLevel::AppOrigin,
+
+        "_get_windows_drives" | "_get_commandline_args" | "get_current_working_dir"
+        | "_get_window_labels" | "get_process_id" | "report_error" | "_get_clipboard_files" | "_open_url_in_browser_win"
+        | "put_item" | "get_item" | "get_all_items" | "delete_item" | "compare_and_set_item"
+        | "update_items" | "get_items" | "get_items_with_prefix" | "storage_stats"
+        | "watch_keys" | "unwatch_keys"
+        | "toggle_devtools" | "show_in_folder" | "zoom_window" | "get_mac_deep_link_requests"
+        | "_color_picker_result" | "pick_screen_color" | "capture_page" | "capture_page_with_options"
+            => TrustLevel::TrustedOrigin,
+
+        _ => TrustLevel::AppOrigin,
+    }
+}
+
+/// A url prefix like "https://phcode.dev/app/", matched by exact scheme, host and port and then
+/// by path prefix. Comparing the parts instead of the strings keeps hosts like
+/// "phcode.dev.evil.com" from passing as "phcode.dev".
+#[derive(Clone, Debug, PartialEq)]
+struct OriginPrefix {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+    path: String,
+}
+
+impl OriginPrefix {
+    fn parse(prefix: &str) -> Option<Self> {
+        let url = Url::parse(prefix).ok()?;
+        Some(OriginPrefix {
+            scheme: url.scheme().to_string(),
+            host: url.host_str()?.to_string(),
+            port: url.port_or_known_default(),
+            path: url.path().to_string(),
+        })
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        url.scheme() == self.scheme
+            // hosts of custom schemes like phtauri aren't lowercased by the parser
+            && url.host_str().map_or(false, |host| host.eq_ignore_ascii_case(&self.host))
+            && url.port_or_known_default() == self.port
+            && url.path().starts_with(self.path.as_str())
+    }
+}
+
+pub struct OriginPolicy {
+    /// The bundled app, like "phtauri://localhost/".
+    app_origin: Option<OriginPrefix>,
+    /// Other trusted pages, like "https://phcode.dev/".
+    trusted_origins: Vec<OriginPrefix>,
+    allow_localhost: bool,
+}
+
+fn parse_origin_prefix(prefix: &str) -> Option<OriginPrefix> {
+    let origin_prefix = OriginPrefix::parse(prefix);
+    if origin_prefix.is_none() {
+        eprintln!("Ignoring invalid trusted origin: {}", prefix);
+    }
+    origin_prefix
+}
+
+impl OriginPolicy {
+    /// Origins that aren't absolute urls with a host are ignored.
+    pub fn new(app_origin: String, trusted_origins: Vec<String>, allow_localhost: bool) -> Self {
+        OriginPolicy {
+            app_origin: parse_origin_prefix(&app_origin),
+            trusted_origins: trusted_origins.iter().filter_map(|origin| parse_origin_prefix(origin)).collect(),
+            allow_localhost,
+        }
+    }
+
+    /// The policy of the app in release `stage`, with `trusted_origins` from the boot config.
+    /// Localhost is only trusted in the dev stage.
+    pub fn for_stage(stage: &str, trusted_origins: Vec<String>) -> Self {
+        Self::new(format!("{}/", phtauri_origin()), trusted_origins, stage == DEV_STAGE)
+    }
+
+    /// The highest trust level a page at `url` has.
+    pub fn trust_level(&self, url: &Url) -> TrustLevel {
+        if self.app_origin.as_ref().map_or(false, |app_origin| app_origin.matches(url)) {
+            return TrustLevel::AppOrigin;
+        }
+        // asset:// is for static file serving only and never has API access
+        if self.allow_localhost && url.scheme() != "asset"
+            && matches!(url.host_str(), Some("localhost") | Some("127.0.0.1")) {
+            return TrustLevel::AppOrigin;
+        }
+        if self.trusted_origins.iter().any(|origin| origin.matches(url)) {
+            return TrustLevel::TrustedOrigin;
+        }
+        TrustLevel::Any
+    }
+
+    /// Decides if a page at `url` may call `command`.
+    pub fn authorize(&self, command: &str, url: &Url) -> Result<(), String> {
+        let required = required_trust_level(command);
+        if self.trust_level(url) >= required {
+            return Ok(());
+        }
+        Err(format!("Blocked call to {} from untrusted origin: {}", command, url))
+    }
+}
+
+static ORIGIN_POLICY: OnceCell<OriginPolicy> = OnceCell::new();
+
+/// Sets the policy used for all commands. Only the first call has an effect.
+pub fn init_origin_policy(policy: OriginPolicy) {
+    let _ = ORIGIN_POLICY.set(policy);
+}
+
+fn origin_policy() -> &'static OriginPolicy {
+    ORIGIN_POLICY.get_or_init(|| OriginPolicy::for_stage(build_stage(), Vec::new()))
+}
+
+/// Checks the command of `invoke` against the url of the calling window. Returns the invoke back
+/// if the call may go ahead, otherwise rejects it.
+pub fn authorize_invoke<R: tauri::Runtime>(invoke: tauri::Invoke<R>) -> Option<tauri::Invoke<R>> {
+    let url = invoke.message.window_ref().url();
+    let command = invoke.message.command();
+    match origin_policy().authorize(command, &url) {
+        Ok(()) => Some(invoke),
+        Err(e) => {
+            let window = invoke.message.window();
+            eprintln!("Denied command {} for window {} at {}", command, window.label(), url);
+            if let Some(audit_command) = audited_command(command) {
+                let scope_name = invoke.message.payload().get("scopeName").and_then(|scope| scope.as_str());
+                credential_audit::record(&window, audit_command, scope_name, AuditOutcome::Denied, Some(&e));
+            }
+            invoke.resolver.reject(e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow_localhost: bool) -> OriginPolicy {
+        OriginPolicy::new("phtauri://localhost/".to_string(),
+                          vec!["https://phcode.dev/".to_string(), "https://example.com/app/".to_string()],
+                          allow_localhost)
+    }
+
+    fn trust_level(policy: &OriginPolicy, url: &str) -> TrustLevel {
+        policy.trust_level(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn app_origin() {
+        let policy = policy(false);
+        assert_eq!(trust_level(&policy, "phtauri://localhost/"), TrustLevel::AppOrigin);
+        assert_eq!(trust_level(&policy, "phtauri://localhost/v5.1.0/index.html?x=1"), TrustLevel::AppOrigin);
+        assert_eq!(trust_level(&policy, "phtauri://LOCALHOST/index.html"), TrustLevel::AppOrigin);
+        assert_eq!(trust_level(&policy, "phtauri://localhostevil/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "phtauri://localhost.evil.com/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "phtauri://localhost:8080/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "https://localhost/"), TrustLevel::Any);
+    }
+
+    #[test]
+    fn trusted_origins_match_host_exactly() {
+        let policy = policy(false);
+        assert_eq!(trust_level(&policy, "https://phcode.dev/"), TrustLevel::TrustedOrigin);
+        assert_eq!(trust_level(&policy, "https://phcode.dev:443/page"), TrustLevel::TrustedOrigin);
+        assert_eq!(trust_level(&policy, "https://phcode.dev.evil.com/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "https://evil.phcode.dev/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "https://phcode.dev@evil.com/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "http://phcode.dev/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "https://phcode.dev:8443/"), TrustLevel::Any);
+    }
+
+    #[test]
+    fn trusted_origins_match_path_prefix() {
+        let policy = policy(false);
+        assert_eq!(trust_level(&policy, "https://example.com/app/index.html"), TrustLevel::TrustedOrigin);
+        assert_eq!(trust_level(&policy, "https://example.com/app/"), TrustLevel::TrustedOrigin);
+        assert_eq!(trust_level(&policy, "https://example.com/other/app/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy, "https://example.com/"), TrustLevel::Any);
+    }
+
+    #[test]
+    fn invalid_trusted_origins_are_ignored() {
+        let policy = OriginPolicy::new("phtauri://localhost/".to_string(),
+                                       vec!["phcode.dev".to_string(), "".to_string()], false);
+        assert!(policy.trusted_origins.is_empty());
+        assert_eq!(trust_level(&policy, "https://phcode.dev/"), TrustLevel::Any);
+    }
+
+    #[test]
+    fn localhost_only_in_dev() {
+        assert_eq!(trust_level(&policy(true), "http://localhost:8000/src/"), TrustLevel::AppOrigin);
+        assert_eq!(trust_level(&policy(true), "http://127.0.0.1:8000/"), TrustLevel::AppOrigin);
+        assert_eq!(trust_level(&policy(true), "http://localhost.evil.com/"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy(true), "asset://localhost/file"), TrustLevel::Any);
+        assert_eq!(trust_level(&policy(false), "http://localhost:8000/src/"), TrustLevel::Any);
+    }
+
+    #[test]
+    fn localhost_is_keyed_on_the_stage() {
+        let localhost = Url::parse("http://localhost:8000/src/").unwrap();
+        assert_eq!(OriginPolicy::for_stage("dev", Vec::new()).trust_level(&localhost), TrustLevel::AppOrigin);
+        assert_eq!(OriginPolicy::for_stage("stage", Vec::new()).trust_level(&localhost), TrustLevel::Any);
+        assert_eq!(OriginPolicy::for_stage("production", Vec::new()).trust_level(&localhost), TrustLevel::Any);
+    }
+
+    #[test]
+    fn authorize_by_command() {
+        let policy = policy(false);
+        let app = Url::parse("phtauri://localhost/").unwrap();
+        let trusted = Url::parse("https://phcode.dev/").unwrap();
+        let other = Url::parse("https://phcode.dev.evil.com/").unwrap();
+        assert!(policy.authorize("get_credential", &app).is_ok());
+        assert!(policy.authorize("get_credential", &trusted).is_err());
+        assert!(policy.authorize("put_item", &trusted).is_ok());
+        assert!(policy.authorize("put_item", &other).is_err());
+        assert!(policy.authorize("console_log", &other).is_ok());
+        assert!(policy.authorize("some_new_command", &trusted).is_err());
+    }
+}
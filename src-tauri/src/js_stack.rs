@@ -0,0 +1,93 @@
+// Parses the `stack` of JS errors into crash report frames. Handles the V8 format of the
+// Windows webview ("    at fn (url:line:col)") and the WebKit/Gecko format of the macOS and
+// Linux webviews ("fn@url:line:col"). Lines that match neither, like the message V8 puts first,
+// are skipped.
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashMap;
+use tauri::Url;
+use crate::crash_report::{redact_path_for_privacy, JsonFrame};
+use crate::utilities::phtauri_origin;
+
+/// Frames past this many are dropped.
+const MAX_JS_FRAMES: usize = 100;
+
+static V8_FRAME: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*at (?:(?P<method>.+?) \()?(?P<file>.+?):(?P<line>\d+):(?P<column>\d+)\)?$").unwrap()
+});
+static WEBKIT_FRAME: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?:(?P<method>[^@]*)@)?(?P<file>.+?):(?P<line>\d+):(?P<column>\d+)$").unwrap()
+});
+// Urls and absolute paths in error messages, like "ENOENT: no such file, open '/home/me/a.txt'".
+// A path has to start the message or follow a space, quote, bracket, '=' or ',' so that "1/2"
+// and the like are left alone.
+static MESSAGE_LOCATION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"(?P<url>\b[a-zA-Z][a-zA-Z0-9+.\-]*://[^\s'"`<>()]+)|(?P<lead>^|[\s'"`(\[=,])(?P<path>(?:[a-zA-Z]:\\|\\\\|~?/)[^\s'"`<>()]+)"#).unwrap()
+});
+
+/// Scheme, host and path of a script url, without the query and fragment that may hold tokens.
+/// Local file paths are redacted like those of native frames.
+pub fn redact_script_url(url: &str) -> String {
+    let Ok(parsed) = Url::parse(url) else {
+        return redact_path_for_privacy(url);
+    };
+    if parsed.scheme() == "file" {
+        return redact_path_for_privacy(parsed.path());
+    }
+    match parsed.host_str() {
+        Some(host) => format!("{}://{}{}", parsed.scheme(), host, parsed.path()),
+        None => format!("{}:{}", parsed.scheme(), parsed.path()),
+    }
+}
+
+/// An error message with the urls and local paths in it redacted like those of stack frames.
+pub fn redact_error_message(message: &str) -> String {
+    MESSAGE_LOCATION.replace_all(message, |captures: &regex::Captures| {
+        match (captures.name("url"), captures.name("path")) {
+            (Some(url), _) => redact_script_url(url.as_str()),
+            (None, Some(path)) => format!("{}{}", &captures["lead"], redact_path_for_privacy(path.as_str())),
+            (None, None) => captures[0].to_string(),
+        }
+    }).to_string()
+}
+
+fn parse_frame(line: &str) -> Option<JsonFrame> {
+    let captures = V8_FRAME.captures(line).or_else(|| WEBKIT_FRAME.captures(line))?;
+    let file = captures.name("file")?.as_str();
+    let method = captures.name("method").map(|method| method.as_str().trim()).unwrap_or("");
+    Some(JsonFrame {
+        file: redact_script_url(file),
+        line_number: captures["line"].parse().unwrap_or(0),
+        column_number: captures["column"].parse().unwrap_or(0),
+        method: if method.is_empty() { "(anonymous)".to_string() } else { method.to_string() },
+        in_project: file.starts_with(phtauri_origin()),
+        code: HashMap::new(),
+    })
+}
+
+/// Frames of a JS `error.stack`, innermost first like native stacks.
+pub fn parse_js_stack(stack: &str) -> Vec<JsonFrame> {
+    stack.lines().filter_map(parse_frame).take(MAX_JS_FRAMES).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_message_urls_lose_query_and_fragment() {
+        assert_eq!(redact_error_message("Failed to fetch https://api.example.com/v1/items?token=s3cret#x"),
+                   "Failed to fetch https://api.example.com/v1/items");
+        assert_eq!(redact_error_message("Loading 'phtauri://localhost/index.html?code=1' failed"),
+                   "Loading 'phtauri://localhost/index.html' failed");
+    }
+
+    #[test]
+    fn error_message_paths_are_redacted() {
+        assert_eq!(redact_error_message("ENOENT: no such file or directory, open '/home/jane/notes/plan.txt'"),
+                   "ENOENT: no such file or directory, open 'plan.txt (path redacted for privacy)'");
+        assert_eq!(redact_error_message("missing /usr/lib/libgtk-3.so"), "missing /usr/lib/libgtk-3.so");
+        assert_eq!(redact_error_message("expected 1/2 of the width"), "expected 1/2 of the width");
+    }
+}
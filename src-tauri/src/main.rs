@@ -41,15 +41,32 @@ extern crate percent_encoding;
 use tauri::http::ResponseBuilder;
 use tauri::GlobalWindowEvent;
 mod init;
+mod crash_report;
+mod breadcrumbs;
+mod crash_spool;
+mod crash_frames;
+mod js_stack;
 mod bugsnag;
+mod http_sink;
 mod utilities;
 mod boot_config;
+mod geometry;
+mod capture;
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+mod color_picker;
 use trash;
 
 mod platform;
+mod storage;
+mod persisted_storage;
+mod ipc_security;
+mod window_trust;
+mod credentials;
+mod credential_vault;
+mod credential_audit;
+use credential_audit::{AuditCommand, AuditOutcome};
+use storage::{BatchOp, CompareAndSetResult, SealedStorageChange, Storage, StorageStats, SEALED_STORAGE_CHANGED_EVENT};
 
-use keyring::Entry;
-use whoami;
 
 #[derive(Clone, serde::Serialize)]
 struct Payload {
@@ -60,8 +77,10 @@ struct Payload {
 // AES Key Trust Management Structure
 #[derive(Clone, Debug)]
 struct AesKeyData {
-    key: String,
-    iv: String,
+    // session key derived in the key exchange
+    key: [u8; 32],
+    // public key the page made the handshake with, needed to remove the trust again
+    page_public_key: String,
 }
 
 struct WindowAesTrust {
@@ -77,6 +96,35 @@ fn console_log(_handle: tauri::AppHandle, message: &str) {
 #[tauri::command]
 fn console_error(_handle: tauri::AppHandle, message: &str) {
     eprintln!("{}", message);
+    // the message can hold file contents or tokens, so only the call is recorded
+    breadcrumbs::leave(breadcrumbs::BreadcrumbType::Log, "console_error");
+}
+
+/// Reports a fatal error of the web app, like an unhandled rejection during boot, through the
+/// crash reporting of native panics. Returns true if a report was saved.
+#[tauri::command]
+async fn report_error(window: tauri::Window, error_class: String, message: String, stack: Option<String>,
+                      severity: Option<String>) -> Result<bool, String> {
+    let js_error = crash_report::JsError {
+        error_class,
+        message,
+        stack: stack.unwrap_or_default(),
+        severity: severity.unwrap_or_else(|| "error".to_string()),
+    };
+    let window_label = window.label().to_string();
+    let window_url = window.url().to_string();
+    // the consent dialog blocks until answered
+    tauri::async_runtime::spawn_blocking(move || {
+        crash_report::report_js_error(js_error, &window_label, &window_url, |description| {
+            let error_message = format!(
+                "Phoenix Code ran into an error: \n\n{}\n\nWould you like to send an anonymised error report to help us fix the problem?",
+                description
+            );
+            show_crash_dialog("Oops! Phoenix Code ran into an error", &error_message, true)
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
 }
 
 #[tauri::command]
@@ -156,38 +204,83 @@ fn _open_url_in_browser_win(url: String, browser: String) -> Result<(), String>
 }
 
 // this in memory hashmap is used to supplement the LMDB node layer in a multi window environment.
-struct Storage {
-    map: Mutex<HashMap<String, String>>,
-}
-
+// see storage.rs
 #[tauri::command]
-fn put_item(state: State<'_, Storage>, key: String, value: String) {
-    let mut map = state.map.lock().unwrap();
-    map.insert(key, value);
+fn put_item(window: tauri::Window, state: State<'_, Storage>, key: String, value: String,
+            ttl_ms: Option<u64>) -> Result<(), String> {
+    let changes = state.put(key, value, ttl_ms)?;
+    state.notify_changes(&window, &changes);
+    Ok(())
 }
 
 #[tauri::command]
 fn get_item(state: State<'_, Storage>, key: String) -> Option<String> {
-    let map = state.map.lock().unwrap();
-    map.get(&key).cloned()
+    state.get_item(&key)
 }
 
 #[tauri::command]
 fn get_all_items(state: State<'_, Storage>) -> HashMap<String, String> {
-    let map = state.map.lock().unwrap();
-    map.clone()
+    state.get_all_items()
+}
+
+#[tauri::command]
+fn delete_item(window: tauri::Window, state: State<'_, Storage>, key: String) {
+    let changes = state.delete(key);
+    state.notify_changes(&window, &changes);
+}
+
+#[tauri::command]
+fn compare_and_set_item(window: tauri::Window, state: State<'_, Storage>, key: String,
+                        expected: Option<String>, value: Option<String>,
+                        ttl_ms: Option<u64>) -> Result<CompareAndSetResult, String> {
+    let (result, changes) = state.compare_and_set(key, expected, value, ttl_ms)?;
+    state.notify_changes(&window, &changes);
+    Ok(result)
+}
+
+#[tauri::command]
+fn update_items(window: tauri::Window, state: State<'_, Storage>, ops: Vec<BatchOp>) -> Result<(), String> {
+    // notify only after the whole batch is applied
+    let changes = state.update(ops)?;
+    state.notify_changes(&window, &changes);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_items(state: State<'_, Storage>, keys: Vec<String>) -> HashMap<String, String> {
+    state.get_items(&keys)
+}
+
+#[tauri::command]
+fn get_items_with_prefix(state: State<'_, Storage>, prefix: String) -> HashMap<String, String> {
+    state.get_items_with_prefix(&prefix)
+}
+
+#[tauri::command]
+fn storage_stats(state: State<'_, Storage>) -> StorageStats {
+    state.stats()
 }
 
+// Once called, the window only gets storage-changed events for keys starting with one of the
+// watched prefixes instead of for every key.
 #[tauri::command]
-fn delete_item(state: State<'_, Storage>, key: String) {
-    let mut map = state.map.lock().unwrap();
-    map.remove(&key);
+fn watch_keys(window: tauri::Window, state: State<'_, Storage>, prefix: String) {
+    state.watch_keys(window.label(), prefix);
+}
+
+// Unwatching the last prefix goes back to storage-changed events for every key.
+#[tauri::command]
+fn unwatch_keys(window: tauri::Window, state: State<'_, Storage>, prefix: String) {
+    state.unwatch_keys(window.label(), &prefix);
 }
 // in memory hashmap end
 
 // AES Key Trust Management Commands
+// Trust is established with an X25519 key exchange, see window_trust.rs. The page sends its public
+// key and gets the native public key back, both sides then derive the same AES-256 session key.
+// Only the app itself can establish trust, see ipc_security.rs.
 #[tauri::command]
-fn trust_window_aes_key(window: tauri::Window, key: String, iv: String, trust_state: State<'_, WindowAesTrust>) -> Result<(), String> {
+fn trust_window_aes_key(window: tauri::Window, public_key: String, trust_state: State<'_, WindowAesTrust>) -> Result<String, String> {
     let window_label = window.label().to_string();
     let mut trust_map = trust_state.trust_map.lock().unwrap();
 
@@ -196,55 +289,28 @@ fn trust_window_aes_key(window: tauri::Window, key: String, iv: String, trust_st
         return Err("Trust has already been established for this window. remove trust to set again.".to_string());
     }
 
-    // Validate AES key format and length
-    let key_bytes = hex::decode(&key).map_err(|_| "Invalid AES key format. Key must be a valid hex string.".to_string())?;
-    if key_bytes.len() != 32 {
-        return Err("Invalid AES key length. Key must be 32 bytes (64 hex characters) for AES-256.".to_string());
-    }
-
-    // Validate nonce format and length
-    let nonce_bytes = hex::decode(&iv).map_err(|_| "Invalid nonce format. Nonce must be a valid hex string.".to_string())?;
-    if nonce_bytes.len() != 12 {
-        return Err("Invalid nonce length. Nonce must be 12 bytes (24 hex characters) for AES-GCM.".to_string());
-    }
-
-    // Test that we can create a valid cipher with these parameters
-    use aes_gcm::{Aes256Gcm, Key};
-    use aes_gcm::aead::KeyInit;
-
-    let aes_key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-    let _cipher = Aes256Gcm::new(aes_key);
-
-    // Just verify we can create a slice of the right size - no need to store it
-    if nonce_bytes.len() == 12 {
-        // This validates that we have the right size for AES-GCM nonce
-    } else {
-        return Err("Invalid nonce length. Nonce must be 12 bytes (24 hex characters) for AES-GCM.".to_string());
-    }
-
-    // If we get here, the key and nonce are valid
-    // Store the AES key and IV for this window
-    trust_map.insert(window_label.clone(), AesKeyData { key, iv });
+    let exchange = window_trust::exchange_keys(&window_label, &public_key)?;
+    trust_map.insert(window_label.clone(), AesKeyData { key: exchange.session_key, page_public_key: public_key });
 
     println!("AES trust established for window: {}", window_label);
-    Ok(())
+    Ok(exchange.public_key)
 }
 
 #[tauri::command]
-fn remove_trust_window_aes_key(window: tauri::Window, key: String, iv: String, trust_state: State<'_, WindowAesTrust>) -> Result<(), String> {
+fn remove_trust_window_aes_key(window: tauri::Window, public_key: String, trust_state: State<'_, WindowAesTrust>) -> Result<(), String> {
     let window_label = window.label().to_string();
     let mut trust_map = trust_state.trust_map.lock().unwrap();
 
     // Check if trust exists for this window
     match trust_map.get(&window_label) {
         Some(stored_data) => {
-            // Verify the provided key and IV match the stored ones
-            if stored_data.key == key && stored_data.iv == iv {
+            // Verify that the caller is the page that made the handshake
+            if stored_data.page_public_key == public_key {
                 trust_map.remove(&window_label);
                 println!("AES trust removed for window: {}", window_label);
                 Ok(())
             } else {
-                Err("Provided key and IV do not match the stored trust data.".to_string())
+                Err("Provided public key does not match the stored trust data.".to_string())
             }
         }
         None => {
@@ -253,19 +319,15 @@ fn remove_trust_window_aes_key(window: tauri::Window, key: String, iv: String, t
     }
 }
 
-static mut DEVTOOLS_LOADED:bool = false;
-
 #[tauri::command]
 fn toggle_devtools(window: tauri::Window) {
-    unsafe {
-        // though unsafe, this is fine as its just a view toggle and not mission critical.
-        if !DEVTOOLS_LOADED {
-            window.open_devtools();
-        } else {
-            window.close_devtools();
-        }
-        DEVTOOLS_LOADED = !DEVTOOLS_LOADED;
+    let devtools_open = init::get_window_view_state(&window).devtools_open;
+    if !devtools_open {
+        window.open_devtools();
+    } else {
+        window.close_devtools();
     }
+    init::update_window_view_state(&window, |view_state| view_state.devtools_open = !devtools_open);
 }
 
 #[tauri::command]
@@ -319,67 +381,38 @@ fn show_in_folder(path: String) {
 
 #[tauri::command]
 fn zoom_window(window: tauri::Window, scale_factor: f64) {
-    let _ = window.with_webview(move |webview| {
-        #[cfg(target_os = "linux")]
-        {
-          // see https://docs.rs/webkit2gtk/0.18.2/webkit2gtk/struct.WebView.html
-          // and https://docs.rs/webkit2gtk/0.18.2/webkit2gtk/trait.WebViewExt.html
-          use webkit2gtk::traits::WebViewExt;
-          webview.inner().set_zoom_level(scale_factor);
-        }
-
-        #[cfg(windows)]
-        unsafe {
-          // see https://docs.rs/webview2-com/0.19.1/webview2_com/Microsoft/Web/WebView2/Win32/struct.ICoreWebView2Controller.html
-          webview.controller().SetZoomFactor(scale_factor).unwrap();
-        }
-
-        #[cfg(target_os = "macos")]
-        unsafe {
-          let () = msg_send![webview.inner(), setPageZoom: scale_factor];
-        }
-      });
+    init::set_webview_zoom(&window, scale_factor);
+    init::update_window_view_state(&window, |view_state| view_state.zoom_level = Some(scale_factor));
 }
 
 fn process_window_event(event: &GlobalWindowEvent, trust_state: &State<WindowAesTrust>) {
+    if let tauri::WindowEvent::Destroyed = event.event() {
+        breadcrumbs::window_closed(event.window().label());
+    }
     if let tauri::WindowEvent::CloseRequested { .. } = event.event() {
         // Remove AES trust for the closing window
         let window_label = event.window().label().to_string();
-        let mut trust_map = trust_state.trust_map.lock().unwrap();
-
-        if trust_map.remove(&window_label).is_some() {
+        // released right away, nothing below should block commands of other windows
+        let removed_trust = trust_state.trust_map.lock().unwrap().remove(&window_label);
+        if removed_trust.is_some() {
             println!("AES trust removed for closing window: {}", window_label);
         }
 
-        // Save main window position/size so it can be restored on next launch
-        if window_label == "main" {
-            let window = event.window();
-            let maximized = window.is_maximized().unwrap_or(false);
-            let (x, y) = match window.outer_position() {
-                Ok(pos) => (pos.x, pos.y),
-                Err(_) => (0, 0),
-            };
-            // On Windows, set_size() sets the inner (client) area, so we must save
-            // inner_size() to avoid the window growing by the decoration size on each restart.
-            // On macOS, outer_size()/set_size() are consistent, so we keep outer_size().
-            let (width, height) = {
-                #[cfg(target_os = "windows")]
-                {
-                    match window.inner_size() {
-                        Ok(size) => (size.width, size.height),
-                        Err(_) => (0, 0),
-                    }
-                }
-                #[cfg(not(target_os = "windows"))]
-                {
-                    match window.outer_size() {
-                        Ok(size) => (size.width, size.height),
-                        Err(_) => (0, 0),
-                    }
-                }
-            };
-            boot_config::write_boot_config(1, x, y, width, height, maximized);
+        let storage = event.window().state::<Storage>();
+        storage.remove_window(&window_label);
+        // the app may exit once the window is gone, get the last batch of writes going. Waiting
+        // for it here would block the event loop, the wait is on exit instead.
+        storage.start_flush();
+
+        #[cfg(any(target_os = "linux", target_os = "windows"))]
+        if window_label == color_picker::OVERLAY_WINDOW_LABEL {
+            // closed without picking a color
+            color_picker::finish_pick(&event.window().app_handle(), String::new());
+            return;
         }
+
+        // Save the window position/size so it can be restored on next launch
+        init::save_window_state(event.window());
     }
 }
 
@@ -416,6 +449,21 @@ fn get_mac_deep_link_requests() -> Vec<String> {
     }
 }
 
+// Called by the color picker overlay window with the picked color, or "" if dismissed.
+#[tauri::command]
+fn _color_picker_result(window: tauri::Window, color: String) {
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    {
+        if window.label() == color_picker::OVERLAY_WINDOW_LABEL {
+            color_picker::finish_pick(&window.app_handle(), color);
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let _ = (&window, &color);
+    }
+}
+
 // Screenshot capture types
 #[derive(serde::Deserialize)]
 struct CaptureRect {
@@ -425,17 +473,18 @@ struct CaptureRect {
     height: f64,
 }
 
-// macOS native screen eyedropper for the styles-bar color picker. WebKit (the
-// app's WKWebView) does not implement the web EyeDropper API, so the previewed
-// page routes here through the editor. Shows the system color sampler
-// (NSColorSampler, macOS 10.15+) and resolves with the picked color as
-// "#rrggbb", or "" when the user dismisses the sampler without choosing.
+// Screen eyedropper for the styles-bar color picker. WebKit (the app's
+// WKWebView) does not implement the web EyeDropper API, so the previewed
+// page routes here through the editor. On macOS this shows the system color
+// sampler (NSColorSampler, macOS 10.15+). Elsewhere the window is captured and
+// covered by a loupe overlay, see color_picker.rs. Resolves with the picked
+// color as "#rrggbb", or "" when the user dismisses the picker without choosing.
 #[tauri::command]
 async fn pick_screen_color(window: tauri::Window) -> Result<String, String> {
     #[cfg(not(target_os = "macos"))]
     {
-        let _ = &window;
-        return Err("pick_screen_color is only implemented on macOS".to_string());
+        let capture_png = capture_page(window.clone(), None).await?;
+        return color_picker::pick_color(window, capture_png).await;
     }
 
     #[cfg(target_os = "macos")]
@@ -507,8 +556,7 @@ async fn pick_screen_color(window: tauri::Window) -> Result<String, String> {
 async fn capture_page(window: tauri::Window, rect: Option<CaptureRect>) -> Result<Vec<u8>, String> {
     #[cfg(target_os = "linux")]
     {
-        let _ = (&window, &rect);
-        return Err("capture_page is not implemented on Linux".to_string());
+        return capture_page_linux(window, rect).await;
     }
 
     #[cfg(target_os = "macos")]
@@ -607,6 +655,156 @@ async fn capture_page(window: tauri::Window, rect: Option<CaptureRect>) -> Resul
     }
 }
 
+// Evaluates a js expression (which may be a promise) in the window and returns its JSON result.
+// Tauri can't return values from eval, so the result is sent back as a one time event of the
+// window. The event name is random so that other pages can't guess it and answer in its place.
+async fn eval_with_result(window: &tauri::Window, expression: &str) -> Result<serde_json::Value, String> {
+    use aes_gcm::aead::{OsRng, rand_core::RngCore};
+    let mut id = [0u8; 16];
+    OsRng.fill_bytes(&mut id);
+    let event_name = format!("phcode-eval-result-{}", hex::encode(id));
+    let (tx, rx) = tokio::sync::oneshot::channel::<Option<String>>();
+    window.once(event_name.clone(), move |event| {
+        let _ = tx.send(event.payload().map(|payload| payload.to_string()));
+    });
+    let script = format!(
+        "(async () => ({{result: await ({expression})}}))()\
+            .catch(err => ({{error: String(err)}}))\
+            .then(result => window.__TAURI__.window.getCurrent().emit('{event_name}', result));",
+        expression = expression, event_name = event_name);
+    window.eval(&script).map_err(|e| e.to_string())?;
+
+    let payload = tokio::time::timeout(std::time::Duration::from_secs(10), rx).await
+        .map_err(|_| "Timed out waiting for the page".to_string())?
+        .map_err(|_| "Eval channel closed".to_string())?
+        .ok_or("Page sent no result")?;
+    let mut payload: serde_json::Value = serde_json::from_str(&payload).map_err(|e| e.to_string())?;
+    if let Some(error) = payload.get("error") {
+        return Err(error.as_str().unwrap_or("Page script failed").to_string());
+    }
+    Ok(payload["result"].take())
+}
+
+// The live preview frame of the app. When it is shown, full page captures scroll and capture the
+// previewed page instead of the app around it.
+const LIVE_PREVIEW_FRAME_SELECTOR: &str = "#panel-live-preview-frame";
+
+// Js expression for what a full page capture scrolls: `target` is the window to scroll and
+// `rect` the frame's content box in CSS pixels of the app, null when scrolling the app itself.
+fn scroll_target_script() -> String {
+    format!(
+        "(() => {{\
+            const frame = document.querySelector('{selector}');\
+            if (!frame || !frame.getClientRects().length) {{\
+                return {{target: window, rect: null}};\
+            }}\
+            try {{\
+                frame.contentWindow.document;\
+            }} catch (e) {{\
+                throw new Error('The live preview is cross origin and can not be scrolled');\
+            }}\
+            const bounds = frame.getBoundingClientRect();\
+            return {{target: frame.contentWindow, rect: {{\
+                x: bounds.x + frame.clientLeft, y: bounds.y + frame.clientTop,\
+                width: frame.clientWidth, height: frame.clientHeight\
+            }}}};\
+        }})()", selector = LIVE_PREVIEW_FRAME_SELECTOR)
+}
+
+// Scrolls the capture target to `scroll_y` CSS pixels and resolves with its metrics once the
+// scrolled content has been painted.
+async fn scroll_page_to(window: &tauri::Window, scroll_y: f64) -> Result<serde_json::Value, String> {
+    eval_with_result(window, &format!(
+        "new Promise(resolve => {{\
+            const {{target, rect}} = {scroll_target};\
+            target.scrollTo({{left: target.scrollX, top: {scroll_y}, behavior: 'instant'}});\
+            requestAnimationFrame(() => requestAnimationFrame(() => resolve({{\
+                scrollY: target.scrollY,\
+                innerHeight: target.innerHeight,\
+                scrollHeight: target.document.documentElement.scrollHeight,\
+                viewportHeight: window.innerHeight,\
+                rect\
+            }})));\
+        }})", scroll_target = scroll_target_script(), scroll_y = scroll_y)).await
+}
+
+// Captures the whole scrollable page by capturing one viewport at a time and stitching the
+// tiles. The original scroll position is restored even if a capture fails.
+async fn capture_full_page(window: &tauri::Window) -> Result<Vec<u8>, String> {
+    let original_scroll_y = eval_with_result(window, &format!("{}.target.scrollY", scroll_target_script())).await?
+        .as_f64().unwrap_or(0.0);
+    let result = capture_page_tiles(window).await;
+    let _ = scroll_page_to(window, original_scroll_y).await;
+    let (tiles, page_height) = result?;
+    tauri::async_runtime::spawn_blocking(move || capture::stitch_tiles(&tiles, page_height))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+async fn capture_page_tiles(window: &tauri::Window) -> Result<(Vec<capture::PageTile>, u32), String> {
+    let mut tiles: Vec<capture::PageTile> = Vec::new();
+    let mut page_height = 0;
+    let mut next_scroll_y = 0.0;
+    let mut last_scroll_y = 0.0;
+    loop {
+        let metrics = scroll_page_to(window, next_scroll_y).await?;
+        let scroll_y = metrics["scrollY"].as_f64().unwrap_or(0.0);
+        let inner_height = metrics["innerHeight"].as_f64().unwrap_or(0.0);
+        let scroll_height = metrics["scrollHeight"].as_f64().unwrap_or(0.0);
+        let viewport_height = metrics["viewportHeight"].as_f64().unwrap_or(0.0);
+        if inner_height <= 0.0 || viewport_height <= 0.0 {
+            return Err("Page has zero height".to_string());
+        }
+
+        // the browser clamps scrolling, so a scroll that did not move means we are at the end
+        if !tiles.is_empty() && scroll_y <= last_scroll_y {
+            break;
+        }
+        last_scroll_y = scroll_y;
+
+        let png_bytes = capture_page(window.clone(), None).await?;
+        let (rgba, full_width, full_height) = capture::decode_png(&png_bytes)?;
+        // physical pixels per CSS pixel, as the capture covers exactly the app's viewport
+        let pixel_ratio = full_height as f64 / viewport_height;
+        let frame_rect = metrics["rect"].as_object().map(|rect| {
+            let css = |key: &str| rect.get(key).and_then(|value| value.as_f64()).unwrap_or(0.0) * pixel_ratio;
+            (css("x"), css("y"), css("width"), css("height"))
+        });
+        let region = capture::clamp_region(frame_rect, full_width, full_height)?;
+        let tile = capture::PageTile {
+            rgba: capture::crop_rgba(&rgba, full_width, &region),
+            width: region.width,
+            height: region.height,
+            offset_y: (scroll_y * pixel_ratio).round() as u32,
+        };
+        page_height = (scroll_height * pixel_ratio).round() as u32;
+        let tile_bottom = tile.offset_y + tile.height;
+        tiles.push(tile);
+
+        next_scroll_y = scroll_y + inner_height;
+        if next_scroll_y >= scroll_height || tile_bottom >= capture::MAX_FULL_PAGE_HEIGHT {
+            break;
+        }
+    }
+    Ok((tiles, page_height))
+}
+
+// Same as capture_page, but the image can be scaled, re-encoded as JPEG/WebP and delivered as
+// base64, a file, a protocol url or to the clipboard instead of a JSON number array.
+#[tauri::command]
+async fn capture_page_with_options(window: tauri::Window, rect: Option<CaptureRect>,
+                                   options: Option<capture::CaptureOptions>) -> Result<capture::CaptureResult, String> {
+    let options = options.unwrap_or_default();
+    let png_bytes = if options.full_page {
+        capture_full_page(&window).await?
+    } else {
+        capture_page(window, rect).await?
+    };
+    tauri::async_runtime::spawn_blocking(move || capture::process_capture(png_bytes, &options))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
 #[cfg(windows)]
 fn capture_page_windows(window: tauri::Window, rect: Option<CaptureRect>) -> Result<Vec<u8>, String> {
     use winapi::um::winuser::{GetDC, ReleaseDC, GetClientRect, PrintWindow};
@@ -665,165 +863,329 @@ fn capture_page_windows(window: tauri::Window, rect: Option<CaptureRect>) -> Res
         // The JS caller already sends the rect in physical pixels (it multiplies
         // getBoundingClientRect() by devicePixelRatio on Windows). Just clamp to
         // the actual client area from GetClientRect, which is also in physical pixels.
-        let (cap_x, cap_y, cap_w, cap_h) = if let Some(r) = &rect {
-            let x = (r.x as i32).max(0).min(full_width);
-            let y = (r.y as i32).max(0).min(full_height);
-            let w = (r.width as i32).min(full_width - x).max(0);
-            let h = (r.height as i32).min(full_height - y).max(0);
-            (x, y, w, h)
-        } else {
-            (0, 0, full_width, full_height)
-        };
+        let region = capture::clamp_region(
+            rect.as_ref().map(|r| (r.x, r.y, r.width, r.height)),
+            full_width as u32, full_height as u32)?;
+        let region_pixels = capture::crop_rgba(&pixels, full_width as u32, &region);
+        capture::encode_png(&region_pixels, region.width, region.height)
+    }
+}
 
-        if cap_w <= 0 || cap_h <= 0 {
-            return Err("Capture region is empty".to_string());
-        }
+#[cfg(target_os = "linux")]
+async fn capture_page_linux(window: tauri::Window, rect: Option<CaptureRect>) -> Result<Vec<u8>, String> {
+    let (tx, rx) = tokio::sync::oneshot::channel::<Result<Vec<u8>, String>>();
+
+    window.with_webview(move |webview| {
+        use webkit2gtk::traits::WebViewExt;
+        use webkit2gtk::{SnapshotOptions, SnapshotRegion};
+
+        let wk_webview = webview.inner();
+        // The snapshot surface is sized in logical pixels and carries the device scale, so
+        // it is rendered into a physical pixel image below.
+        let logical_width = wk_webview.allocated_width();
+        let logical_height = wk_webview.allocated_height();
+        let device_pixel_ratio = wk_webview.scale_factor() as f64;
+
+        wk_webview.snapshot(
+            SnapshotRegion::Visible,
+            SnapshotOptions::NONE,
+            None::<&webkit2gtk::gio::Cancellable>,
+            move |result| {
+                let png = result
+                    .map_err(|e| e.to_string())
+                    .and_then(|surface| snapshot_to_png(
+                        &surface, logical_width, logical_height, device_pixel_ratio, rect.as_ref()));
+                let _ = tx.send(png);
+            },
+        );
+    }).map_err(|e| e.to_string())?;
 
-        let mut region_pixels = Vec::with_capacity((cap_w * cap_h * 4) as usize);
-        for y in cap_y..(cap_y + cap_h) {
-            let start = ((y * full_width + cap_x) * 4) as usize;
-            let end = start + (cap_w * 4) as usize;
-            region_pixels.extend_from_slice(&pixels[start..end]);
-        }
+    rx.await.map_err(|_| "Capture channel closed".to_string())?
+}
 
-        // Encode as PNG
-        let mut png_bytes: Vec<u8> = Vec::new();
-        {
-            let mut encoder = png::Encoder::new(&mut png_bytes, cap_w as u32, cap_h as u32);
-            encoder.set_color(png::ColorType::Rgba);
-            encoder.set_depth(png::BitDepth::Eight);
-            let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
-            writer.write_image_data(&region_pixels).map_err(|e| e.to_string())?;
-        }
-        Ok(png_bytes)
+// Unlike on Windows, the JS caller sends the rect in CSS pixels here (same as macOS), so it is
+// scaled by the device pixel ratio before being clamped to the physical image.
+#[cfg(target_os = "linux")]
+fn snapshot_to_png(surface: &gtk::cairo::Surface, logical_width: i32, logical_height: i32,
+                   device_pixel_ratio: f64, rect: Option<&CaptureRect>) -> Result<Vec<u8>, String> {
+    use gtk::cairo::{Context, Format, ImageSurface};
+
+    if logical_width <= 0 || logical_height <= 0 {
+        return Err("Webview has zero area".to_string());
+    }
+    let full_width = (logical_width as f64 * device_pixel_ratio).round() as i32;
+    let full_height = (logical_height as f64 * device_pixel_ratio).round() as i32;
+
+    let mut image = ImageSurface::create(Format::ARgb32, full_width, full_height)
+        .map_err(|e| e.to_string())?;
+    {
+        let cr = Context::new(&image).map_err(|e| e.to_string())?;
+        cr.scale(device_pixel_ratio, device_pixel_ratio);
+        cr.set_source_surface(surface, 0.0, 0.0).map_err(|e| e.to_string())?;
+        cr.paint().map_err(|e| e.to_string())?;
     }
+    image.flush();
+    let stride = image.stride() as usize;
+    let pixels = {
+        let data = image.data().map_err(|e| e.to_string())?;
+        capture::premultiplied_bgra_to_rgba(&data, full_width as u32, full_height as u32, stride)
+    };
+
+    let region = capture::clamp_region(
+        rect.map(|r| (r.x * device_pixel_ratio, r.y * device_pixel_ratio,
+                      r.width * device_pixel_ratio, r.height * device_pixel_ratio)),
+        full_width as u32, full_height as u32)?;
+    let region_pixels = capture::crop_rgba(&pixels, full_width as u32, &region);
+    capture::encode_png(&region_pixels, region.width, region.height)
 }
 
-const PHOENIX_CRED_PREFIX: &str = "phcode_";
+// Stores the secret value securely in the system keyring. `expires_at` is an optional unix time in
+// milliseconds after which the credential is deleted.
+#[tauri::command]
+fn store_credential(window: tauri::Window, scope_name: String, secret_val: String, expires_at: Option<u64>) -> Result<(), String> {
+    let result = credentials::store(&scope_name, &secret_val, expires_at);
+    credential_audit::record_result(&window, AuditCommand::Store, Some(&scope_name), &result, |_| true);
+    result
+}
 
-fn get_username() -> String {
-    // Ensure a fallback username in case retrieval fails
-    match whoami::username().as_str() {
-        "" => "unknown_user".to_string(), // Fallback if username is empty
-        username => username.to_string(), // Otherwise, use the retrieved username
+// Deletes a stored credential securely
+#[tauri::command]
+fn delete_credential(window: tauri::Window, scope_name: String) -> Result<(), String> {
+    let result = credentials::delete(&scope_name);
+    credential_audit::record_result(&window, AuditCommand::Delete, Some(&scope_name), &result, |existed| *existed);
+    match result? {
+        true => Ok(()),
+        false => Err(keyring::Error::NoEntry.to_string()),
     }
 }
 
-// Stores the secret value securely in the system keyring
+// Lists the stored credential scopes with their timestamps, never the secrets.
 #[tauri::command]
-fn store_credential(scope_name: String, secret_val: String) -> Result<(), String> {
-    let service = format!("{}{}", PHOENIX_CRED_PREFIX, scope_name); // Unique service name per scope
-    let user = get_username();
+fn list_credential_scopes(window: tauri::Window) -> Vec<credentials::CredentialInfo> {
+    credential_audit::record(&window, AuditCommand::List, None, AuditOutcome::Success, None);
+    credentials::list_scopes()
+}
 
-    let entry = Entry::new(&service, &user).map_err(|e| e.to_string())?;
-    entry.set_password(&secret_val).map_err(|e| e.to_string())?;
+// Deletes all stored credentials, for sign out. Returns how many were deleted.
+#[tauri::command]
+fn delete_all_credentials(window: tauri::Window) -> Result<usize, String> {
+    let result = credentials::delete_all();
+    credential_audit::record_result(&window, AuditCommand::DeleteAll, None, &result, |_| true);
+    result
+}
 
-    Ok(())
+// Returns the newest credential audit log entries matching the filter, see credential_audit.rs.
+#[tauri::command]
+fn get_credential_audit_log(filter: Option<credential_audit::AuditFilter>) -> Vec<credential_audit::AuditEntry> {
+    credential_audit::query(&filter.unwrap_or_default())
 }
 
-// Deletes a stored credential securely
+// Tells if credentials go to the OS keyring or to the encrypted vault file, see credentials.rs.
 #[tauri::command]
-fn delete_credential(scope_name: String) -> Result<(), String> {
-    let service = format!("{}{}", PHOENIX_CRED_PREFIX, scope_name);
-    let user = get_username();
+fn credential_backend_info() -> credentials::BackendInfo {
+    credentials::backend_info()
+}
 
-    let entry = Entry::new(&service, &user).map_err(|e| e.to_string())?;
-    entry.delete_password().map_err(|e| e.to_string())?;
+// Opens the credential vault when it is protected by a passphrase. For a new vault, this sets
+// the passphrase.
+#[tauri::command]
+fn unlock_credential_vault(passphrase: String) -> Result<(), String> {
+    credentials::unlock_vault(&passphrase)
+}
 
-    Ok(())
+// Sets or, with None, removes the passphrase of the unlocked credential vault.
+#[tauri::command]
+fn set_credential_vault_passphrase(passphrase: Option<String>) -> Result<(), String> {
+    credentials::set_vault_passphrase(passphrase.as_deref())
 }
 
 // Gets the stored credential, encrypts it with the window's AES key, and returns the encrypted value
+// with its nonce. The associated data is "credential:<window label>:<scope name>".
 // Returns None if no credential is found
 #[tauri::command]
-fn get_credential(window: tauri::Window, scope_name: String, trust_state: State<'_, WindowAesTrust>) -> Result<Option<String>, String> {
-    let window_label = window.label().to_string();
-
+fn get_credential(window: tauri::Window, scope_name: String, trust_state: State<'_, WindowAesTrust>) -> Result<Option<EncryptedValue>, String> {
     // Check if AES trust is established for this window
-    let trust_map = trust_state.trust_map.lock().unwrap();
-    let aes_data = match trust_map.get(&window_label) {
-        Some(data) => data.clone(),
-        None => {
-            return Err("Trust needs to be first established by calling the trust_window_aes_key API to use this API.".to_string());
+    let aes_data = match get_window_aes_trust(&window, &trust_state) {
+        Ok(aes_data) => aes_data,
+        Err(e) => {
+            credential_audit::record(&window, AuditCommand::Get, Some(&scope_name), AuditOutcome::Denied, Some(&e));
+            return Err(e);
         }
     };
-    drop(trust_map); // Release the lock early
 
-    // Retrieve the stored credential
-    let service = format!("{}{}", PHOENIX_CRED_PREFIX, scope_name);
-    let user = get_username();
-    let entry = Entry::new(&service, &user).map_err(|e| e.to_string())?;
+    // Retrieve the stored credential and encrypt it for the window
+    let result = credentials::get(&scope_name).and_then(|stored_credential| match stored_credential {
+        Some(data) => encrypt_for_window(&aes_data, &data, &credential_aad(window.label(), &scope_name)).map(Some),
+        None => Ok(None), // Return None if no credential found
+    });
+    credential_audit::record_result(&window, AuditCommand::Get, Some(&scope_name), &result, Option::is_some);
+    result
+}
+
+/// AES-GCM output handed to a trusted window. Every response is encrypted under a fresh random
+/// nonce, which the window needs to decrypt it.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EncryptedValue {
+    nonce: String,
+    ciphertext: String,
+}
 
-    let stored_credential = match entry.get_password() {
-        Ok(data) => data,
-        Err(keyring::Error::NoEntry) => return Ok(None), // Return None if no credential found
-        Err(e) => return Err(format!("Failed to retrieve credential: {}", e.to_string())),
-    };
+// Associated data binding a credential response to the window and scope it was asked for, so that
+// it can't be replayed to another window or passed off as another scope's secret.
+fn credential_aad(window_label: &str, scope_name: &str) -> String {
+    format!("credential:{}:{}", window_label, scope_name)
+}
 
-    // Encrypt the credential using AES-GCM
-    use aes_gcm::{Aes256Gcm, Key, Nonce};
-    use aes_gcm::aead::{Aead, KeyInit};
+fn sealed_item_aad(window_label: &str, key: &str) -> String {
+    format!("sealed:{}:{}", window_label, key)
+}
 
-    // Decode the key and nonce from hex strings
-    let key_bytes = hex::decode(&aes_data.key).map_err(|_| "Invalid AES key format".to_string())?;
-    let nonce_bytes = hex::decode(&aes_data.iv).map_err(|_| "Invalid nonce format".to_string())?;
+// Encrypts `plaintext` with the window's trusted AES key under a new random nonce. Nonces are
+// never reused, as reusing one with the same key breaks AES-GCM.
+fn encrypt_for_window(aes_data: &AesKeyData, plaintext: &str, aad: &str) -> Result<EncryptedValue, String> {
+    use aes_gcm::{AeadCore, Aes256Gcm, Key};
+    use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
 
-    if key_bytes.len() != 32 {
-        return Err("AES key must be 32 bytes (256 bits)".to_string());
+    let key = Key::<Aes256Gcm>::from_slice(&aes_data.key);
+    let cipher = Aes256Gcm::new(key);
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let encrypted_data = cipher.encrypt(&nonce, Payload { msg: plaintext.as_bytes(), aad: aad.as_bytes() })
+        .map_err(|_| "Failed to encrypt value".to_string())?;
+    Ok(EncryptedValue { nonce: hex::encode(nonce), ciphertext: hex::encode(encrypted_data) })
+}
+
+fn get_window_aes_trust(window: &tauri::Window, trust_state: &State<'_, WindowAesTrust>) -> Result<AesKeyData, String> {
+    get_label_aes_trust(window.label(), trust_state)
+}
+
+fn get_label_aes_trust(window_label: &str, trust_state: &WindowAesTrust) -> Result<AesKeyData, String> {
+    let trust_map = trust_state.trust_map.lock().unwrap();
+    match trust_map.get(window_label) {
+        Some(data) => Ok(data.clone()),
+        None => Err("Trust needs to be first established by calling the trust_window_aes_key API to use this API.".to_string()),
     }
-    if nonce_bytes.len() != 12 {
-        return Err("Nonce must be 12 bytes for AES-GCM".to_string());
+}
+
+// Tells the other trusted windows that a sealed key changed. The value is never sent.
+fn notify_sealed_change(window: &tauri::Window, trust_state: &State<'_, WindowAesTrust>, change: SealedStorageChange) {
+    let trusted_labels: Vec<String> = trust_state.trust_map.lock().unwrap().keys().cloned().collect();
+    for label in trusted_labels {
+        if label == window.label() {
+            continue;
+        }
+        if let Some(trusted_window) = window.get_window(&label) {
+            if let Err(e) = trusted_window.emit(SEALED_STORAGE_CHANGED_EVENT, change.clone()) {
+                eprintln!("Failed to send sealed storage change to window {}: {}", label, e);
+            }
+        }
     }
+}
 
-    // Create cipher
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
-    let cipher = Aes256Gcm::new(key);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+// The sealed namespace of Storage holds secrets like session tokens shared between windows. Only
+// windows that established trust with trust_window_aes_key can use it, and reads are encrypted
+// with the window's AES key like get_credential, with "sealed:<window label>:<key>" as the
+// associated data.
+#[tauri::command]
+fn put_sealed_item(window: tauri::Window, key: String, value: String,
+                   state: State<'_, Storage>, trust_state: State<'_, WindowAesTrust>) -> Result<(), String> {
+    get_window_aes_trust(&window, &trust_state)?;
+    if state.put_sealed(key.clone(), value) {
+        notify_sealed_change(&window, &trust_state, SealedStorageChange { key, deleted: false });
+    }
+    Ok(())
+}
 
-    // Encrypt the credential
-    let encrypted_data = cipher.encrypt(nonce, stored_credential.as_bytes())
-        .map_err(|_| "Failed to encrypt credential".to_string())?;
+#[tauri::command]
+fn get_sealed_item(window: tauri::Window, key: String,
+                   state: State<'_, Storage>, trust_state: State<'_, WindowAesTrust>) -> Result<Option<EncryptedValue>, String> {
+    read_sealed_item(&state, &trust_state, window.label(), &key)
+}
 
-    // Return the encrypted data as a hex string wrapped in Some
-    Ok(Some(hex::encode(encrypted_data)))
+// The sealed value of `key` encrypted for the window `window_label`, refused if the window has no
+// trust.
+fn read_sealed_item(state: &Storage, trust_state: &WindowAesTrust, window_label: &str,
+                    key: &str) -> Result<Option<EncryptedValue>, String> {
+    let aes_data = get_label_aes_trust(window_label, trust_state)?;
+    match state.get_sealed(key) {
+        Some(value) => encrypt_for_window(&aes_data, &value, &sealed_item_aad(window_label, key)).map(Some),
+        None => Ok(None),
+    }
+}
+
+#[tauri::command]
+fn delete_sealed_item(window: tauri::Window, key: String,
+                      state: State<'_, Storage>, trust_state: State<'_, WindowAesTrust>) -> Result<(), String> {
+    get_window_aes_trust(&window, &trust_state)?;
+    if state.delete_sealed(&key) {
+        notify_sealed_change(&window, &trust_state, SealedStorageChange { key, deleted: true });
+    }
+    Ok(())
+}
+
+/// Shows an error dialog. If `ask_to_send`, it asks whether to send an error report and returns
+/// the answer, false if the dialog couldn't be shown. Otherwise it only informs and returns true.
+fn show_crash_dialog(title: &str, error_message: &str, ask_to_send: bool) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        if ask_to_send {
+            let choice = dialog::Question::new(error_message)
+                .title(title)
+                .show();
+            return matches!(choice, Ok(dialog::Choice::Yes));
+        }
+        if let Err(e) = dialog::Message::new(error_message).title(title).show() {
+            eprintln!("Could not display dialog box: {}", e);
+        }
+        true
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "windows"))]
+    {
+        let dialog = MessageDialog::new()
+            .set_type(MessageType::Error)
+            .set_title(title)
+            .set_text(error_message);
+        if ask_to_send {
+            return dialog.show_confirm().unwrap_or(false);
+        }
+        if let Err(e) = dialog.show_alert() {
+            eprintln!("Could not display dialog box: {}", e);
+        }
+        true
+    }
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     panic::set_hook(Box::new(|panic_info| {
-        let panic_message = panic_info.payload().downcast_ref::<&str>().unwrap_or(&"No specific error message available");
-        eprintln!("Application panicked: {:?}", panic_message);
+        let panic_details = crash_report::PanicDetails::new(panic_info.payload(), panic_info.location());
+        eprintln!("Application panicked: {}", panic_details.describe());
 
-        // Construct the error message with the panic message included
-        let error_message = format!(
-            "The app crashed unexpectedly with error: \n\n'{}'\n\nOpening support page. Would you like to send an anonymised error report to help us fix the problem?",
-            panic_message
-        );
+        let args: Vec<String> = env::args().collect();
+        let run_verify = args.contains(&"--runVerify".to_string());
+        // only ask about sending a report if crash reporting is configured to send one, and the
+        // user hasn't answered that already this run, for an earlier panic or a JS error
+        let consent = crash_report::report_consent();
+        let ask_to_send = crash_report::sends_reports() && !run_verify && consent.is_none();
 
-        let should_log_to_bugsnag;
-        #[cfg(target_os = "linux")]
-        {
-            let choice = dialog::Question::new(&error_message)
-                .title("Oops! Phoenix Code Crashed :(")
-                .show()
-                .expect("Could not display dialog box");
-            if choice == dialog::Choice::Yes {
-                should_log_to_bugsnag = true;
-            } else {
-                should_log_to_bugsnag = false;
-            }
-        }
+        // Construct the error message with the panic message included
+        let error_message = if ask_to_send {
+            format!(
+                "The app crashed unexpectedly with error: \n\n{}\n\nOpening support page. Would you like to send an anonymised error report to help us fix the problem?",
+                panic_details.describe()
+            )
+        } else {
+            format!("The app crashed unexpectedly with error: \n\n{}\n\nOpening support page.", panic_details.describe())
+        };
 
-        #[cfg(any(target_os = "macos", target_os = "windows"))]
-        {
-            should_log_to_bugsnag = MessageDialog::new()
-                .set_type(MessageType::Error)
-                .set_title("Oops! Phoenix Code Crashed :(")
-                .set_text(&error_message)
-                .show_confirm()
-                .unwrap();
+        let answer = show_crash_dialog("Oops! Phoenix Code Crashed :(", &error_message, ask_to_send);
+        if ask_to_send {
+            crash_report::set_report_consent(answer);
         }
+        let should_report = consent.unwrap_or(answer);
 
         // take user to support page. maybe we will have some notifications pinned there if its a large scale outage
         let support_url = "https://github.com/orgs/phcode-dev/discussions";
@@ -832,14 +1194,9 @@ fn main() {
         } else {
             println!("Failed to open support_url {}.", support_url);
         }
-        let args: Vec<String> = env::args().collect();
-        if should_log_to_bugsnag && !args.contains(&"--runVerify".to_string()) {
-            let message = if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
-                s.to_string()
-            } else {
-                "Unknown panic message".to_string()
-            };
-            bugsnag::handle(&message);
+        // without asking, the report is only kept on disk, see crash_report.rs
+        if should_report && crash_report::is_enabled() && !run_verify {
+            crash_report::handle(&panic_details);
         }
     }));
 
@@ -867,16 +1224,19 @@ fn main() {
     let _ = fix_path_env::fix();
 
     tauri::Builder::default()
-        .manage(Storage {
-                map: Mutex::new(HashMap::new()),
-        })
+        .manage(Storage::new())
         .manage(WindowAesTrust {
             trust_map: Mutex::new(HashMap::new()),
         })
+        .manage(init::WindowViewStates {
+            view_map: Mutex::new(HashMap::new()),
+        })
         .register_uri_scheme_protocol("phtauri", move |app, request| { // can't use `tauri` because that's already in use
             let path = remove_version_from_url(request.uri());
             let path = path.strip_prefix("phtauri://localhost");
             if path.is_none() {
+                breadcrumbs::leave_with_metadata(breadcrumbs::BreadcrumbType::Request, "phtauri 404",
+                    &[("url", &js_stack::redact_script_url(request.uri()))]);
                 let not_found_response = ResponseBuilder::new()
                     .status(404)
                     .mimetype("text/html")
@@ -892,8 +1252,24 @@ fn main() {
             let path_without_query_or_fragment = path.split('?').next().unwrap_or(&path);
             let final_path = path_without_query_or_fragment.split('#').next().unwrap_or(path_without_query_or_fragment).to_string();
 
+            if let Some((mime_type, bytes)) = capture::cached_capture(&final_path) {
+                let response = ResponseBuilder::new()
+                    .mimetype(mime_type)
+                    .body(bytes)?;
+                return Ok(response);
+            }
+            #[cfg(any(target_os = "linux", target_os = "windows"))]
+            if let Some((mime_type, bytes)) = color_picker::overlay_asset(&final_path) {
+                let response = ResponseBuilder::new()
+                    .mimetype(mime_type)
+                    .body(bytes)?;
+                return Ok(response);
+            }
+
             let asset_option = app.asset_resolver().get(final_path.clone());
             if asset_option.is_none() {
+                breadcrumbs::leave_with_metadata(breadcrumbs::BreadcrumbType::Request, "phtauri 404",
+                    &[("url", &js_stack::redact_script_url(&format!("{}{}", utilities::phtauri_origin(), final_path)))]);
                 let not_found_response = ResponseBuilder::new()
                     .status(404)
                     .mimetype("text/html")
@@ -928,6 +1304,9 @@ fn main() {
 
                     app.emit_all("single-instance", Payload { args: argv, cwd }).unwrap();
                 }))
+        .plugin(tauri::plugin::Builder::new("windowState")
+            .on_webview_ready(|window| init::restore_window_state_on_create(&window))
+            .build())
         .on_page_load(|window, _payload| {
             // Disable browser accelerator keys (F5 reload, Ctrl+R, etc.) in all webviews
             // to prevent the window from reloading on F5 key press
@@ -944,8 +1323,13 @@ fn main() {
                     }
                 });
             }
-            #[cfg(not(target_os = "windows"))]
-            let _ = window; // suppress unused warning on non-windows
+            // A new document has to make its own key exchange, the trust of the previous page
+            // must not carry over to whatever was navigated to.
+            let trust_state = window.state::<WindowAesTrust>();
+            if trust_state.trust_map.lock().unwrap().remove(window.label()).is_some() {
+                println!("AES trust removed on page load for window: {}", window.label());
+            }
+            breadcrumbs::page_loaded(window.label());
         })
         .on_window_event(|event| {
             // Get the trust state from the app handle
@@ -953,15 +1337,32 @@ fn main() {
             let trust_state = app_handle.state::<WindowAesTrust>();
             process_window_event(&event, &trust_state);
         })
-        .invoke_handler(tauri::generate_handler![
-            get_mac_deep_link_requests, get_process_id,
-            toggle_devtools, console_log, console_error, _get_commandline_args, get_current_working_dir,
-            _get_window_labels,
-            store_credential, get_credential, delete_credential,
-            put_item, get_item, get_all_items, delete_item,
-            trust_window_aes_key, remove_trust_window_aes_key,
-            _get_windows_drives, _rename_path, show_in_folder, move_to_trash, zoom_window,
-            _get_clipboard_files, _open_url_in_browser_win, capture_page, pick_screen_color])
+        .invoke_handler({
+            let handler = tauri::generate_handler![
+                get_mac_deep_link_requests, get_process_id,
+                toggle_devtools, console_log, console_error, report_error, _get_commandline_args, get_current_working_dir,
+                _get_window_labels,
+                store_credential, get_credential, delete_credential, list_credential_scopes, delete_all_credentials,
+                credential_backend_info, unlock_credential_vault, set_credential_vault_passphrase,
+                get_credential_audit_log,
+                put_item, get_item, get_all_items, delete_item, watch_keys, unwatch_keys,
+                compare_and_set_item, update_items, get_items, get_items_with_prefix, storage_stats,
+                put_sealed_item, get_sealed_item, delete_sealed_item,
+                trust_window_aes_key, remove_trust_window_aes_key,
+                _get_windows_drives, _rename_path, show_in_folder, move_to_trash, zoom_window,
+                _get_clipboard_files, _open_url_in_browser_win, capture_page, capture_page_with_options, pick_screen_color, _color_picker_result]);
+            // every command is checked against the origin of the calling page first
+            move |invoke| {
+                let command = invoke.message.command();
+                // console logging is too chatty, console_error leaves its own breadcrumb
+                if command != "console_log" && command != "console_error" {
+                    breadcrumbs::leave(breadcrumbs::BreadcrumbType::Request, command);
+                }
+                if let Some(invoke) = ipc_security::authorize_invoke(invoke) {
+                    handler(invoke);
+                }
+            }
+        })
         .setup(|app| {
             init::init_app(app);
             #[cfg(target_os = "linux")]
@@ -994,6 +1395,63 @@ fn main() {
             }
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app_handle, event| {
+            if let tauri::RunEvent::Exit = event {
+                // don't lose persisted writes still in the writer's batch
+                app_handle.state::<Storage>().flush();
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
+
+    fn decrypt(key: &[u8; 32], encrypted: &EncryptedValue, aad: &str) -> Result<Vec<u8>, aes_gcm::Error> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        let nonce = hex::decode(&encrypted.nonce).unwrap();
+        let ciphertext = hex::decode(&encrypted.ciphertext).unwrap();
+        cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: aad.as_bytes() })
+    }
+
+    #[test]
+    fn encrypt_for_window_never_reuses_a_nonce() {
+        let aes_data = AesKeyData { key: [7u8; 32], page_public_key: String::new() };
+        let aad = credential_aad("main", "github");
+        let first = encrypt_for_window(&aes_data, "secret", &aad).unwrap();
+        let second = encrypt_for_window(&aes_data, "secret", &aad).unwrap();
+        assert_eq!(first.nonce.len(), 24);
+        assert_ne!(first.nonce, second.nonce);
+        assert_ne!(first.ciphertext, second.ciphertext);
+
+        assert_eq!(decrypt(&aes_data.key, &first, &aad).unwrap(), b"secret");
+        assert_eq!(decrypt(&aes_data.key, &second, &aad).unwrap(), b"secret");
+        // bound to the window and scope it was asked for
+        assert!(decrypt(&aes_data.key, &first, &credential_aad("phcode-1", "github")).is_err());
+        assert!(decrypt(&aes_data.key, &first, &sealed_item_aad("main", "github")).is_err());
+    }
+
+    #[test]
+    fn sealed_reads_need_trust_and_are_encrypted() {
+        let storage = Storage::new();
+        storage.put_sealed("session".to_string(), "token-123".to_string());
+        let trust_state = WindowAesTrust { trust_map: Mutex::new(HashMap::new()) };
+        assert!(read_sealed_item(&storage, &trust_state, "main", "session").is_err());
+
+        let aes_data = AesKeyData { key: [9u8; 32], page_public_key: String::new() };
+        trust_state.trust_map.lock().unwrap().insert("main".to_string(), aes_data.clone());
+        let encrypted = read_sealed_item(&storage, &trust_state, "main", "session").unwrap().unwrap();
+        assert!(!encrypted.ciphertext.contains(&hex::encode("token-123")));
+        assert_eq!(decrypt(&aes_data.key, &encrypted, "sealed:main:session").unwrap(), b"token-123");
+        // bound to the window and key it was read for
+        assert!(decrypt(&aes_data.key, &encrypted, "sealed:phcode-1:session").is_err());
+        assert!(decrypt(&aes_data.key, &encrypted, "sealed:main:other").is_err());
+
+        assert!(read_sealed_item(&storage, &trust_state, "main", "missing").unwrap().is_none());
+        assert!(read_sealed_item(&storage, &trust_state, "phcode-1", "session").is_err());
+    }
 }
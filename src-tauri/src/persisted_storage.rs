@@ -0,0 +1,263 @@
+// Durable backing for the `Storage` keys under the persisted prefix. Changes are appended to a
+// JSON lines log in app_local_data_dir by a background thread that batches writes, and the log
+// is compacted into a snapshot of the live entries once it has grown enough. This makes
+// boot-critical settings available at startup, before the node layer is up.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use crate::utilities::write_file_atomic;
+
+static STORAGE_LOG_FILE_NAME: &'static str = "storage.log";
+/// Writes arriving within this window are appended and fsynced together.
+const BATCH_INTERVAL: Duration = Duration::from_millis(200);
+/// Compact once the log holds this many more records than there are live entries.
+const COMPACTION_SLACK_RECORDS: usize = 1000;
+/// How long `wait_for_flush` waits for the writer.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum LogRecord {
+    Put {
+        key: String,
+        value: String,
+        /// Unix time in milliseconds after which the entry is gone, see `Storage` TTLs.
+        #[serde(default, rename = "expiresAt", skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+    },
+    Delete { key: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct PersistedEntry {
+    pub value: String,
+    pub expires_at: Option<u64>,
+}
+
+enum Message {
+    Record(LogRecord),
+    Flush(Sender<()>),
+}
+
+pub struct PersistedStorage {
+    pub prefix: String,
+    sender: Sender<Message>,
+}
+
+fn apply_record(entries: &mut HashMap<String, PersistedEntry>, record: LogRecord) {
+    match record {
+        LogRecord::Put { key, value, expires_at } => {
+            entries.insert(key, PersistedEntry { value, expires_at });
+        }
+        LogRecord::Delete { key } => {
+            entries.remove(&key);
+        }
+    }
+}
+
+/// Replays the log. A partially written last line, as left by a crash, is skipped.
+fn load_log(log_file_path: &PathBuf) -> (HashMap<String, PersistedEntry>, usize) {
+    let mut entries = HashMap::new();
+    let mut record_count = 0;
+    let file = match File::open(log_file_path) {
+        Ok(file) => file,
+        Err(_) => return (entries, record_count),
+    };
+    for line in BufReader::new(file).lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        match serde_json::from_str::<LogRecord>(&line) {
+            Ok(record) => {
+                apply_record(&mut entries, record);
+                record_count += 1;
+            }
+            Err(e) => eprintln!("Skipping corrupt storage log record: {}", e),
+        }
+    }
+    (entries, record_count)
+}
+
+fn to_log_line(record: &LogRecord) -> String {
+    // serializing a plain enum of strings can't fail
+    let mut line = serde_json::to_string(record).unwrap();
+    line.push('\n');
+    line
+}
+
+fn compact(log_file_path: &PathBuf, entries: &HashMap<String, PersistedEntry>) -> std::io::Result<()> {
+    let mut contents = String::new();
+    for (key, entry) in entries {
+        contents.push_str(&to_log_line(&LogRecord::Put {
+            key: key.clone(),
+            value: entry.value.clone(),
+            expires_at: entry.expires_at,
+        }));
+    }
+    write_file_atomic(log_file_path, contents.as_bytes())
+}
+
+fn append(log_file_path: &PathBuf, records: &[LogRecord]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(log_file_path)?;
+    let contents: String = records.iter().map(to_log_line).collect();
+    file.write_all(contents.as_bytes())?;
+    file.sync_data()
+}
+
+fn run_writer(log_file_path: PathBuf, mut entries: HashMap<String, PersistedEntry>, mut record_count: usize,
+              receiver: Receiver<Message>) {
+    // Start from a clean snapshot, so that new records are never appended to a partially written
+    // line left by a crash. Records sent meanwhile wait in the channel.
+    match compact(&log_file_path, &entries) {
+        Ok(()) => record_count = entries.len(),
+        Err(e) => eprintln!("Failed to compact storage log {}: {}", log_file_path.display(), e),
+    }
+    let mut pending: Vec<LogRecord> = Vec::new();
+    let mut flush_waiters: Vec<Sender<()>> = Vec::new();
+    loop {
+        // block until there is work, then keep collecting until the batch interval is over
+        let message = match receiver.recv() {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let batch_deadline = Instant::now() + BATCH_INTERVAL;
+        let mut next_message = Some(message);
+        let mut disconnected = false;
+        while let Some(message) = next_message.take() {
+            match message {
+                Message::Record(record) => pending.push(record),
+                Message::Flush(waiter) => {
+                    flush_waiters.push(waiter);
+                    // someone is waiting, write right away
+                    break;
+                }
+            }
+            let timeout = batch_deadline.saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(message) => next_message = Some(message),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => disconnected = true,
+            }
+        }
+
+        if !pending.is_empty() {
+            if let Err(e) = append(&log_file_path, &pending) {
+                eprintln!("Failed to write storage log {}: {}", log_file_path.display(), e);
+            }
+            record_count += pending.len();
+            for record in pending.drain(..) {
+                apply_record(&mut entries, record);
+            }
+            if record_count > entries.len() + COMPACTION_SLACK_RECORDS {
+                match compact(&log_file_path, &entries) {
+                    Ok(()) => record_count = entries.len(),
+                    Err(e) => eprintln!("Failed to compact storage log {}: {}", log_file_path.display(), e),
+                }
+            }
+        }
+        for waiter in flush_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+impl PersistedStorage {
+    /// Loads the persisted entries from `dir` and starts the background writer. Returns the
+    /// loaded entries so that they can be put into the in memory map.
+    pub fn open(dir: &PathBuf, prefix: String) -> (PersistedStorage, HashMap<String, PersistedEntry>) {
+        let mut log_file_path = dir.clone();
+        log_file_path.push(STORAGE_LOG_FILE_NAME);
+        let (mut entries, record_count) = load_log(&log_file_path);
+        // entries persisted under a previously configured prefix are not ours anymore
+        entries.retain(|key, _| key.starts_with(prefix.as_str()));
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Failed to create storage log dir: {}", e);
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let writer_entries = entries.clone();
+        std::thread::spawn(move || run_writer(log_file_path, writer_entries, record_count, receiver));
+        (PersistedStorage { prefix, sender }, entries)
+    }
+
+    pub fn is_persisted(&self, key: &str) -> bool {
+        key.starts_with(self.prefix.as_str())
+    }
+
+    pub fn record(&self, record: LogRecord) {
+        let _ = self.sender.send(Message::Record(record));
+    }
+
+    /// Asks the writer to write everything recorded so far right away. The returned receiver
+    /// fires once it is on disk, see `wait_for_flush`. None if the writer is gone.
+    pub fn start_flush(&self) -> Option<Receiver<()>> {
+        let (sender, receiver) = mpsc::channel();
+        self.sender.send(Message::Flush(sender)).ok()?;
+        Some(receiver)
+    }
+}
+
+/// Blocks until the flush started with `start_flush` is done, or for at most `FLUSH_TIMEOUT`.
+pub fn wait_for_flush(flushed: Receiver<()>) {
+    let _ = flushed.recv_timeout(FLUSH_TIMEOUT);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utilities::test_dir;
+
+    #[test]
+    fn flush_writes_records_made_before_it() {
+        let dir = test_dir("persisted-storage-flush");
+        let (persisted, entries) = PersistedStorage::open(&dir, "persisted.".to_string());
+        assert!(entries.is_empty());
+        persisted.record(LogRecord::Put { key: "persisted.a".to_string(), value: "1".to_string(), expires_at: None });
+        persisted.record(LogRecord::Delete { key: "persisted.b".to_string() });
+        wait_for_flush(persisted.start_flush().unwrap());
+
+        let (entries, record_count) = load_log(&dir.join(STORAGE_LOG_FILE_NAME));
+        assert_eq!(record_count, 2);
+        assert_eq!(entries["persisted.a"].value, "1");
+        drop(persisted);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn startup_compaction_keeps_records_made_meanwhile() {
+        let dir = test_dir("persisted-storage-compaction");
+        fs::create_dir_all(&dir).unwrap();
+        let log_file_path = dir.join(STORAGE_LOG_FILE_NAME);
+        let old_records = [
+            LogRecord::Put { key: "persisted.a".to_string(), value: "1".to_string(), expires_at: None },
+            LogRecord::Put { key: "persisted.a".to_string(), value: "2".to_string(), expires_at: None },
+            LogRecord::Put { key: "other.b".to_string(), value: "3".to_string(), expires_at: None },
+        ];
+        append(&log_file_path, &old_records).unwrap();
+        // a partial line as left by a crash
+        OpenOptions::new().append(true).open(&log_file_path).unwrap().write_all(b"{\"op\":\"pu").unwrap();
+
+        let (persisted, entries) = PersistedStorage::open(&dir, "persisted.".to_string());
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries["persisted.a"].value, "2");
+        persisted.record(LogRecord::Put { key: "persisted.c".to_string(), value: "4".to_string(), expires_at: None });
+        wait_for_flush(persisted.start_flush().unwrap());
+
+        let contents = fs::read_to_string(&log_file_path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        let (entries, _) = load_log(&log_file_path);
+        assert_eq!(entries["persisted.a"].value, "2");
+        assert_eq!(entries["persisted.c"].value, "4");
+        assert!(!entries.contains_key("other.b"));
+        drop(persisted);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
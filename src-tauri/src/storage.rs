@@ -0,0 +1,762 @@
+// In memory key value store shared by all windows. It supplements the LMDB node layer in a
+// multi window environment: writes are broadcast to the other windows as `storage-changed`
+// events so that they don't have to poll. Keys under the persisted prefix also survive restarts,
+// see persisted_storage.rs.
+// Items can expire after a TTL, and the store is kept under a byte budget by evicting the least
+// recently used keys. Expiry and eviction are broadcast like any other delete.
+// Secrets go to a separate sealed namespace that is never persisted, listed or broadcast with
+// values. The commands in main.rs only hand it to windows with AES trust, encrypted.
+
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::Manager;
+use crate::persisted_storage::{wait_for_flush, LogRecord, PersistedStorage};
+use crate::utilities::unix_time_millis;
+
+pub const STORAGE_CHANGED_EVENT: &str = "storage-changed";
+/// Sent with just the key, as the value must not leave the sealed namespace in plaintext.
+pub const SEALED_STORAGE_CHANGED_EVENT: &str = "sealed-storage-changed";
+pub const DEFAULT_STORAGE_MAX_BYTES: usize = 64 * 1024 * 1024;
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const STATS_LARGEST_KEYS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeReason {
+    Write,
+    Expired,
+    Evicted,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageChange {
+    pub key: String,
+    pub old_value: Option<String>,
+    /// None if the key was deleted, expired or evicted.
+    pub new_value: Option<String>,
+    pub reason: ChangeReason,
+    /// Increases with every change, in the order the changes were made. Events can reach a
+    /// window out of order, so a change with a lower sequence than the last one applied for the
+    /// same key is stale.
+    pub sequence: u64,
+}
+
+/// One write of an `update_items` batch.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum BatchOp {
+    #[serde(rename_all = "camelCase")]
+    Put { key: String, value: String, ttl_ms: Option<u64> },
+    Delete { key: String },
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CompareAndSetResult {
+    pub swapped: bool,
+    /// The value after the call, so that a caller that lost the race can retry without a read.
+    pub current_value: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeySize {
+    pub key: String,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    pub key_count: usize,
+    pub total_bytes: usize,
+    /// 0 if unlimited.
+    pub max_bytes: usize,
+    pub expiring_key_count: usize,
+    pub largest_keys: Vec<KeySize>,
+}
+
+struct Entry {
+    value: String,
+    /// Unix time in milliseconds.
+    expires_at: Option<u64>,
+    /// Position in `StorageMap.lru_order`, None for persisted keys, which are never evicted.
+    last_access: Option<u64>,
+}
+
+fn entry_size(key: &str, value: &str) -> usize {
+    key.len() + value.len()
+}
+
+fn expiry_from_ttl(ttl_ms: Option<u64>) -> Option<u64> {
+    ttl_ms.map(|ttl| unix_time_millis().saturating_add(ttl))
+}
+
+/// The entries plus the indexes needed to find the next expiring and the least recently used
+/// keys without scanning.
+#[derive(Default)]
+struct StorageMap {
+    entries: HashMap<String, Entry>,
+    // access tick -> key, least recently used first
+    lru_order: BTreeMap<u64, String>,
+    // (expiry time, key), soonest first
+    expiry_order: BTreeSet<(u64, String)>,
+    next_tick: u64,
+    next_sequence: u64,
+    total_bytes: usize,
+    max_bytes: usize,
+}
+
+impl StorageMap {
+    fn is_live(entry: &Entry, now: u64) -> bool {
+        entry.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+
+    fn peek(&self, key: &str, now: u64) -> Option<&String> {
+        self.entries.get(key).filter(|entry| Self::is_live(entry, now)).map(|entry| &entry.value)
+    }
+
+    fn touch(&mut self, key: &str) {
+        let tick = self.next_tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            if let Some(last_access) = entry.last_access {
+                self.lru_order.remove(&last_access);
+                self.lru_order.insert(tick, key.to_string());
+                entry.last_access = Some(tick);
+                self.next_tick += 1;
+            }
+        }
+    }
+
+    fn get(&mut self, key: &str, now: u64) -> Option<String> {
+        let value = self.peek(key, now).cloned()?;
+        self.touch(key);
+        Some(value)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(last_access) = entry.last_access {
+            self.lru_order.remove(&last_access);
+        }
+        if let Some(expires_at) = entry.expires_at {
+            self.expiry_order.remove(&(expires_at, key.to_string()));
+        }
+        self.total_bytes -= entry_size(key, &entry.value);
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: String, value: String, expires_at: Option<u64>, evictable: bool) -> Option<Entry> {
+        let old_entry = self.remove(&key);
+        let last_access = if evictable {
+            let tick = self.next_tick;
+            self.next_tick += 1;
+            self.lru_order.insert(tick, key.clone());
+            Some(tick)
+        } else {
+            None
+        };
+        if let Some(expires_at) = expires_at {
+            self.expiry_order.insert((expires_at, key.clone()));
+        }
+        self.total_bytes += entry_size(&key, &value);
+        self.entries.insert(key, Entry { value, expires_at, last_access });
+        old_entry
+    }
+
+    fn next_expired(&self, now: u64) -> Option<String> {
+        self.expiry_order.iter().next()
+            .filter(|(expires_at, _)| *expires_at <= now)
+            .map(|(_, key)| key.clone())
+    }
+
+    fn over_budget(&self) -> bool {
+        self.max_bytes != 0 && self.total_bytes > self.max_bytes
+    }
+
+    // a change with the next sequence number, made while the map is locked
+    fn change(&mut self, key: String, old_value: Option<String>, new_value: Option<String>,
+              reason: ChangeReason) -> StorageChange {
+        self.next_sequence += 1;
+        StorageChange { key, old_value, new_value, reason, sequence: self.next_sequence }
+    }
+}
+
+pub struct Storage {
+    map: Mutex<StorageMap>,
+    // window label -> key prefixes the window watches. Windows without an entry, because they
+    // never called `watch_keys` or unwatched every prefix, are notified of every change.
+    watched_prefixes: Mutex<HashMap<String, Vec<String>>>,
+    persisted: Mutex<Option<PersistedStorage>>,
+    sealed: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SealedStorageChange {
+    pub key: String,
+    pub deleted: bool,
+}
+
+impl Storage {
+    pub fn new() -> Self {
+        Storage {
+            map: Mutex::new(StorageMap { max_bytes: DEFAULT_STORAGE_MAX_BYTES, ..Default::default() }),
+            watched_prefixes: Mutex::new(HashMap::new()),
+            persisted: Mutex::new(None),
+            sealed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the byte budget of the store, 0 for unlimited. Keys and values count towards it.
+    pub fn set_max_bytes(&self, max_bytes: usize) -> Vec<StorageChange> {
+        let mut map = self.map.lock().unwrap();
+        map.max_bytes = max_bytes;
+        let mut changes = Vec::new();
+        self.evict_locked(&mut map, &mut changes);
+        changes
+    }
+
+    /// Loads the keys starting with `prefix` from `dir` and persists changes to them from now on.
+    pub fn enable_persistence(&self, dir: &PathBuf, prefix: String) {
+        if prefix.is_empty() {
+            eprintln!("Storage persistence needs a key prefix, not persisting anything");
+            return;
+        }
+        let mut map = self.map.lock().unwrap();
+        let mut persisted = self.persisted.lock().unwrap();
+        if persisted.is_some() {
+            return;
+        }
+        let (persisted_storage, entries) = PersistedStorage::open(dir, prefix);
+        let now = unix_time_millis();
+        for (key, entry) in entries {
+            if !entry.expires_at.map_or(true, |expires_at| expires_at > now) {
+                persisted_storage.record(LogRecord::Delete { key });
+                continue;
+            }
+            if !map.entries.contains_key(&key) {
+                map.insert(key, entry.value, entry.expires_at, false);
+            }
+        }
+        *persisted = Some(persisted_storage);
+    }
+
+    /// Waits for pending writes of persisted keys to reach the disk. Only the writes made before
+    /// the call are waited for, and the wait happens without holding the persisted lock so that
+    /// other windows can keep writing meanwhile.
+    pub fn flush(&self) {
+        let flushed = self.persisted.lock().unwrap().as_ref().and_then(|persisted| persisted.start_flush());
+        if let Some(flushed) = flushed {
+            wait_for_flush(flushed);
+        }
+    }
+
+    /// Starts writing the pending writes of persisted keys without waiting for them, see `flush`.
+    pub fn start_flush(&self) {
+        if let Some(persisted) = self.persisted.lock().unwrap().as_ref() {
+            persisted.start_flush();
+        }
+    }
+
+    fn is_persisted(&self, key: &str) -> bool {
+        self.persisted.lock().unwrap().as_ref().map_or(false, |persisted| persisted.is_persisted(key))
+    }
+
+    // called with the map locked so that the log sees changes in the same order as the map
+    fn record(&self, record: LogRecord) {
+        if let Some(persisted) = self.persisted.lock().unwrap().as_ref() {
+            let key = match &record {
+                LogRecord::Put { key, .. } | LogRecord::Delete { key } => key,
+            };
+            if persisted.is_persisted(key) {
+                persisted.record(record);
+            }
+        }
+    }
+
+    fn check_size(map: &StorageMap, key: &str, value: &str) -> Result<(), String> {
+        let size = entry_size(key, value);
+        if map.max_bytes != 0 && size > map.max_bytes {
+            return Err(format!("Storage item {} is {} bytes, over the {} bytes budget", key, size, map.max_bytes));
+        }
+        Ok(())
+    }
+
+    fn remove_expired_locked(&self, map: &mut StorageMap, changes: &mut Vec<StorageChange>) {
+        let now = unix_time_millis();
+        while let Some(key) = map.next_expired(now) {
+            if let Some(entry) = map.remove(&key) {
+                self.record(LogRecord::Delete { key: key.clone() });
+                let change = map.change(key, Some(entry.value), None, ChangeReason::Expired);
+                changes.push(change);
+            }
+        }
+    }
+
+    fn evict_locked(&self, map: &mut StorageMap, changes: &mut Vec<StorageChange>) {
+        while map.over_budget() {
+            let Some(key) = map.lru_order.values().next().cloned() else {
+                // only persisted keys are left
+                break;
+            };
+            if let Some(entry) = map.remove(&key) {
+                let change = map.change(key, Some(entry.value), None, ChangeReason::Evicted);
+                changes.push(change);
+            }
+        }
+    }
+
+    // A put without a TTL clears any expiry the key had.
+    fn put_locked(&self, map: &mut StorageMap, key: String, value: String, expires_at: Option<u64>,
+                  changes: &mut Vec<StorageChange>) {
+        let evictable = !self.is_persisted(&key);
+        let old_entry = map.insert(key.clone(), value.clone(), expires_at, evictable);
+        let old_value = old_entry.map(|entry| entry.value);
+        if old_value.as_ref() == Some(&value) {
+            // still recorded, the expiry may have changed
+            self.record(LogRecord::Put { key, value, expires_at });
+            return;
+        }
+        self.record(LogRecord::Put { key: key.clone(), value: value.clone(), expires_at });
+        changes.push(map.change(key, old_value, Some(value), ChangeReason::Write));
+    }
+
+    fn delete_locked(&self, map: &mut StorageMap, key: String, changes: &mut Vec<StorageChange>) {
+        let Some(old_entry) = map.remove(&key) else {
+            return;
+        };
+        self.record(LogRecord::Delete { key: key.clone() });
+        changes.push(map.change(key, Some(old_entry.value), None, ChangeReason::Write));
+    }
+
+    /// Returns the changes to broadcast, which include keys that expired or had to be evicted.
+    pub fn put(&self, key: String, value: String, ttl_ms: Option<u64>) -> Result<Vec<StorageChange>, String> {
+        let mut map = self.map.lock().unwrap();
+        Self::check_size(&map, &key, &value)?;
+        let mut changes = Vec::new();
+        self.remove_expired_locked(&mut map, &mut changes);
+        self.put_locked(&mut map, key, value, expiry_from_ttl(ttl_ms), &mut changes);
+        self.evict_locked(&mut map, &mut changes);
+        Ok(changes)
+    }
+
+    pub fn delete(&self, key: String) -> Vec<StorageChange> {
+        let mut map = self.map.lock().unwrap();
+        let mut changes = Vec::new();
+        self.remove_expired_locked(&mut map, &mut changes);
+        self.delete_locked(&mut map, key, &mut changes);
+        changes
+    }
+
+    /// Sets `key` to `new_value` only if it currently holds `expected`. A None `expected` means the
+    /// key must be absent and a None `new_value` deletes the key.
+    pub fn compare_and_set(&self, key: String, expected: Option<String>, new_value: Option<String>,
+                           ttl_ms: Option<u64>) -> Result<(CompareAndSetResult, Vec<StorageChange>), String> {
+        let mut map = self.map.lock().unwrap();
+        if let Some(value) = &new_value {
+            Self::check_size(&map, &key, value)?;
+        }
+        let mut changes = Vec::new();
+        self.remove_expired_locked(&mut map, &mut changes);
+        if map.peek(&key, unix_time_millis()) != expected.as_ref() {
+            let current_value = map.peek(&key, unix_time_millis()).cloned();
+            return Ok((CompareAndSetResult { swapped: false, current_value }, changes));
+        }
+        match new_value.clone() {
+            Some(value) => self.put_locked(&mut map, key, value, expiry_from_ttl(ttl_ms), &mut changes),
+            None => self.delete_locked(&mut map, key, &mut changes),
+        };
+        self.evict_locked(&mut map, &mut changes);
+        Ok((CompareAndSetResult { swapped: true, current_value: new_value }, changes))
+    }
+
+    /// Applies all `ops` in order under a single lock, so no other window sees a partial batch.
+    /// Nothing is applied if any put is over the byte budget.
+    pub fn update(&self, ops: Vec<BatchOp>) -> Result<Vec<StorageChange>, String> {
+        let mut map = self.map.lock().unwrap();
+        for op in &ops {
+            if let BatchOp::Put { key, value, .. } = op {
+                Self::check_size(&map, key, value)?;
+            }
+        }
+        let mut changes = Vec::new();
+        self.remove_expired_locked(&mut map, &mut changes);
+        for op in ops {
+            match op {
+                BatchOp::Put { key, value, ttl_ms } =>
+                    self.put_locked(&mut map, key, value, expiry_from_ttl(ttl_ms), &mut changes),
+                BatchOp::Delete { key } => self.delete_locked(&mut map, key, &mut changes),
+            };
+        }
+        self.evict_locked(&mut map, &mut changes);
+        Ok(changes)
+    }
+
+    /// Removes the expired keys and returns the changes to broadcast.
+    pub fn remove_expired(&self) -> Vec<StorageChange> {
+        let mut map = self.map.lock().unwrap();
+        let mut changes = Vec::new();
+        self.remove_expired_locked(&mut map, &mut changes);
+        changes
+    }
+
+    pub fn get_item(&self, key: &str) -> Option<String> {
+        self.map.lock().unwrap().get(key, unix_time_millis())
+    }
+
+    /// Returns all live items. Unlike the other reads this doesn't count as a use for eviction.
+    pub fn get_all_items(&self) -> HashMap<String, String> {
+        let map = self.map.lock().unwrap();
+        let now = unix_time_millis();
+        map.entries.iter()
+            .filter(|(_, entry)| StorageMap::is_live(entry, now))
+            .map(|(key, entry)| (key.clone(), entry.value.clone()))
+            .collect()
+    }
+
+    /// Returns the values of the `keys` that exist.
+    pub fn get_items(&self, keys: &[String]) -> HashMap<String, String> {
+        let mut map = self.map.lock().unwrap();
+        let now = unix_time_millis();
+        keys.iter()
+            .filter_map(|key| map.get(key, now).map(|value| (key.clone(), value)))
+            .collect()
+    }
+
+    pub fn get_items_with_prefix(&self, prefix: &str) -> HashMap<String, String> {
+        let mut map = self.map.lock().unwrap();
+        let now = unix_time_millis();
+        let keys: Vec<String> = map.entries.keys().filter(|key| key.starts_with(prefix)).cloned().collect();
+        keys.into_iter()
+            .filter_map(|key| map.get(&key, now).map(|value| (key, value)))
+            .collect()
+    }
+
+    pub fn stats(&self) -> StorageStats {
+        let map = self.map.lock().unwrap();
+        let mut largest_keys: Vec<KeySize> = map.entries.iter()
+            .map(|(key, entry)| KeySize { key: key.clone(), bytes: entry_size(key, &entry.value) })
+            .collect();
+        largest_keys.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        largest_keys.truncate(STATS_LARGEST_KEYS);
+        StorageStats {
+            key_count: map.entries.len(),
+            total_bytes: map.total_bytes,
+            max_bytes: map.max_bytes,
+            expiring_key_count: map.expiry_order.len(),
+            largest_keys,
+        }
+    }
+
+    /// Key count and total bytes, None if the map is locked. For the crash reporter, which can't
+    /// wait on a lock the panicking thread may hold.
+    pub fn try_size(&self) -> Option<(usize, usize)> {
+        let map = self.map.try_lock().ok()?;
+        Some((map.entries.len(), map.total_bytes))
+    }
+
+    /// Returns true if the sealed value changed.
+    pub fn put_sealed(&self, key: String, value: String) -> bool {
+        let mut sealed = self.sealed.lock().unwrap();
+        sealed.insert(key, value.clone()).as_ref() != Some(&value)
+    }
+
+    pub fn get_sealed(&self, key: &str) -> Option<String> {
+        self.sealed.lock().unwrap().get(key).cloned()
+    }
+
+    /// Returns true if the key existed.
+    pub fn delete_sealed(&self, key: &str) -> bool {
+        self.sealed.lock().unwrap().remove(key).is_some()
+    }
+
+    pub fn watch_keys(&self, window_label: &str, prefix: String) {
+        let mut watched_prefixes = self.watched_prefixes.lock().unwrap();
+        let prefixes = watched_prefixes.entry(window_label.to_string()).or_default();
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
+
+    /// Stops watching `prefix`. A window that unwatches its last prefix is notified of every
+    /// change again, like a window that never watched any.
+    pub fn unwatch_keys(&self, window_label: &str, prefix: &str) {
+        let mut watched_prefixes = self.watched_prefixes.lock().unwrap();
+        if let Some(prefixes) = watched_prefixes.get_mut(window_label) {
+            prefixes.retain(|watched| watched != prefix);
+            if prefixes.is_empty() {
+                watched_prefixes.remove(window_label);
+            }
+        }
+    }
+
+    /// Forgets the watches of a closed window.
+    pub fn remove_window(&self, window_label: &str) {
+        self.watched_prefixes.lock().unwrap().remove(window_label);
+    }
+
+    fn is_watching(&self, window_label: &str, key: &str) -> bool {
+        let watched_prefixes = self.watched_prefixes.lock().unwrap();
+        match watched_prefixes.get(window_label) {
+            Some(prefixes) => prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())),
+            None => true,
+        }
+    }
+
+    fn emit_change(&self, windows: HashMap<String, tauri::Window>, skip_label: Option<&str>,
+                   change: &StorageChange) {
+        for (label, window) in windows {
+            if Some(label.as_str()) == skip_label || !self.is_watching(&label, &change.key) {
+                continue;
+            }
+            if let Err(e) = window.emit(STORAGE_CHANGED_EVENT, change) {
+                eprintln!("Failed to send storage change to window {}: {}", label, e);
+            }
+        }
+    }
+
+    /// Sends `storage-changed` to every window watching the key, except the window that wrote it.
+    /// Expiry and eviction are sent to the writer as well, as it didn't ask for them.
+    pub fn notify_change(&self, writer: &tauri::Window, change: &StorageChange) {
+        let skip_label = if change.reason == ChangeReason::Write { Some(writer.label()) } else { None };
+        self.emit_change(writer.windows(), skip_label, change);
+    }
+
+    pub fn notify_changes(&self, writer: &tauri::Window, changes: &[StorageChange]) {
+        for change in changes {
+            self.notify_change(writer, change);
+        }
+    }
+
+    /// Sends `storage-changed` to every window watching the key, for changes no window made.
+    pub fn notify_change_to_all(&self, app: &tauri::AppHandle, change: &StorageChange) {
+        self.emit_change(app.windows(), None, change);
+    }
+}
+
+/// Periodically removes expired keys and broadcasts their removal.
+pub fn start_expiry_task(app: tauri::AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let storage = app.state::<Storage>();
+            for change in storage.remove_expired() {
+                storage.notify_change_to_all(&app, &change);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequences(changes: &[StorageChange]) -> Vec<u64> {
+        changes.iter().map(|change| change.sequence).collect()
+    }
+
+    #[test]
+    fn changes_are_numbered_in_order() {
+        let storage = Storage::new();
+        let first = storage.put("a".to_string(), "1".to_string(), None).unwrap();
+        let second = storage.put("a".to_string(), "2".to_string(), None).unwrap();
+        let unchanged = storage.put("a".to_string(), "2".to_string(), None).unwrap();
+        let deleted = storage.delete("a".to_string());
+        assert_eq!(sequences(&first), vec![1]);
+        assert_eq!(sequences(&second), vec![2]);
+        assert!(unchanged.is_empty());
+        assert_eq!(sequences(&deleted), vec![3]);
+    }
+
+    #[test]
+    fn batch_and_eviction_changes_are_numbered_in_order() {
+        let storage = Storage::new();
+        storage.set_max_bytes(10);
+        let changes = storage.update(vec![
+            BatchOp::Put { key: "a".to_string(), value: "12345".to_string(), ttl_ms: None },
+            BatchOp::Put { key: "b".to_string(), value: "12345".to_string(), ttl_ms: None },
+        ]).unwrap();
+        // "a" is evicted after both writes
+        assert_eq!(changes.iter().map(|change| (change.key.as_str(), change.reason)).collect::<Vec<_>>(),
+                   vec![("a", ChangeReason::Write), ("b", ChangeReason::Write), ("a", ChangeReason::Evicted)]);
+        assert_eq!(sequences(&changes), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn windows_without_watched_prefixes_see_every_key() {
+        let storage = Storage::new();
+        assert!(storage.is_watching("main", "anything"));
+
+        storage.watch_keys("main", "editor.".to_string());
+        storage.watch_keys("main", "theme.".to_string());
+        assert!(storage.is_watching("main", "editor.fontSize"));
+        assert!(!storage.is_watching("main", "anything"));
+        assert!(storage.is_watching("other", "anything"));
+
+        storage.unwatch_keys("main", "editor.");
+        assert!(!storage.is_watching("main", "editor.fontSize"));
+        storage.unwatch_keys("main", "theme.");
+        assert!(storage.is_watching("main", "anything"));
+    }
+
+    fn put(storage: &Storage, key: &str, value: &str) {
+        storage.put(key.to_string(), value.to_string(), None).unwrap();
+    }
+
+    fn items(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn compare_and_set_swaps_only_on_match() {
+        let storage = Storage::new();
+        let (result, changes) = storage.compare_and_set("lock".to_string(), None, Some("window-1".to_string()), None).unwrap();
+        assert!(result.swapped);
+        assert_eq!(result.current_value.as_deref(), Some("window-1"));
+        assert_eq!(changes.len(), 1);
+
+        // another window lost the race for the absent key
+        let (result, changes) = storage.compare_and_set("lock".to_string(), None, Some("window-2".to_string()), None).unwrap();
+        assert!(!result.swapped);
+        assert_eq!(result.current_value.as_deref(), Some("window-1"));
+        assert!(changes.is_empty());
+
+        let (result, _) = storage.compare_and_set("lock".to_string(), Some("window-2".to_string()), None, None).unwrap();
+        assert!(!result.swapped);
+        assert_eq!(storage.get_item("lock").as_deref(), Some("window-1"));
+
+        // a None new value deletes the key
+        let (result, changes) = storage.compare_and_set("lock".to_string(), Some("window-1".to_string()), None, None).unwrap();
+        assert!(result.swapped);
+        assert_eq!(result.current_value, None);
+        assert_eq!(changes[0].new_value, None);
+        assert_eq!(storage.get_item("lock"), None);
+    }
+
+    #[test]
+    fn update_applies_all_or_nothing() {
+        let storage = Storage::new();
+        storage.set_max_bytes(10);
+        put(&storage, "a", "1");
+
+        let rejected = storage.update(vec![
+            BatchOp::Put { key: "b".to_string(), value: "2".to_string(), ttl_ms: None },
+            BatchOp::Delete { key: "a".to_string() },
+            BatchOp::Put { key: "c".to_string(), value: "over the budget".to_string(), ttl_ms: None },
+        ]);
+        assert!(rejected.is_err());
+        assert_eq!(storage.get_all_items(), items(&[("a", "1")]));
+
+        let changes = storage.update(vec![
+            BatchOp::Put { key: "b".to_string(), value: "2".to_string(), ttl_ms: None },
+            BatchOp::Delete { key: "a".to_string() },
+        ]).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(storage.get_all_items(), items(&[("b", "2")]));
+    }
+
+    #[test]
+    fn get_items_returns_existing_keys() {
+        let storage = Storage::new();
+        put(&storage, "editor.fontSize", "14");
+        put(&storage, "editor.theme", "dark");
+        put(&storage, "editorial", "x");
+        put(&storage, "project.root", "/home");
+
+        let keys = ["editor.fontSize".to_string(), "missing".to_string(), "project.root".to_string()];
+        assert_eq!(storage.get_items(&keys), items(&[("editor.fontSize", "14"), ("project.root", "/home")]));
+        assert_eq!(storage.get_items_with_prefix("editor."),
+                   items(&[("editor.fontSize", "14"), ("editor.theme", "dark")]));
+        assert!(storage.get_items_with_prefix("nothing.").is_empty());
+    }
+
+    #[test]
+    fn items_expire_at_their_deadline() {
+        let mut map = StorageMap::default();
+        map.insert("session".to_string(), "token".to_string(), Some(1_000), true);
+        map.insert("kept".to_string(), "value".to_string(), None, true);
+        assert_eq!(map.get("session", 999).as_deref(), Some("token"));
+        assert_eq!(map.get("session", 1_000), None);
+        assert_eq!(map.next_expired(999), None);
+        assert_eq!(map.next_expired(1_000).as_deref(), Some("session"));
+    }
+
+    #[test]
+    fn remove_expired_reports_expired_keys() {
+        let storage = Storage::new();
+        storage.put("kept".to_string(), "value".to_string(), Some(60_000)).unwrap();
+        // puts remove expired keys as well, so this one has to come last
+        storage.put("session".to_string(), "token".to_string(), Some(0)).unwrap();
+        assert_eq!(storage.get_item("session"), None);
+
+        let changes = storage.remove_expired();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].key, "session");
+        assert_eq!(changes[0].old_value.as_deref(), Some("token"));
+        assert_eq!(changes[0].reason, ChangeReason::Expired);
+        assert_eq!(storage.get_all_items(), items(&[("kept", "value")]));
+        assert_eq!(storage.stats().expiring_key_count, 1);
+    }
+
+    #[test]
+    fn reads_refresh_the_eviction_order() {
+        let storage = Storage::new();
+        storage.set_max_bytes(6);
+        put(&storage, "a", "1");
+        put(&storage, "b", "1");
+        put(&storage, "c", "1");
+        // "a" becomes the most recently used, leaving "b" as the least
+        assert_eq!(storage.get_item("a").as_deref(), Some("1"));
+
+        let changes = storage.put("d".to_string(), "1".to_string(), None).unwrap();
+        let evicted: Vec<&str> = changes.iter()
+            .filter(|change| change.reason == ChangeReason::Evicted)
+            .map(|change| change.key.as_str())
+            .collect();
+        assert_eq!(evicted, ["b"]);
+        assert_eq!(storage.get_items_with_prefix("").len(), 3);
+        assert_eq!(storage.get_item("b"), None);
+    }
+
+    #[test]
+    fn stats_list_the_largest_keys() {
+        let storage = Storage::new();
+        for size in 1..=12 {
+            put(&storage, &format!("key{:02}", size), &"x".repeat(size));
+        }
+        storage.put("ttl".to_string(), "x".to_string(), Some(60_000)).unwrap();
+
+        let stats = storage.stats();
+        assert_eq!(stats.key_count, 13);
+        assert_eq!(stats.total_bytes, (1..=12).map(|size| 5 + size).sum::<usize>() + 4);
+        assert_eq!(stats.max_bytes, DEFAULT_STORAGE_MAX_BYTES);
+        assert_eq!(stats.expiring_key_count, 1);
+        assert_eq!(stats.largest_keys.len(), STATS_LARGEST_KEYS);
+        assert_eq!(stats.largest_keys[0].key, "key12");
+        assert_eq!(stats.largest_keys[0].bytes, 17);
+        assert!(stats.largest_keys.windows(2).all(|pair| pair[0].bytes >= pair[1].bytes));
+        assert_eq!(stats.largest_keys.last().unwrap().key, "key03");
+    }
+
+    #[test]
+    fn unsealed_reads_never_return_sealed_items() {
+        let storage = Storage::new();
+        put(&storage, "editor.fontSize", "14");
+        storage.put_sealed("editor.token".to_string(), "secret".to_string());
+        storage.put_sealed("session".to_string(), "secret".to_string());
+
+        assert_eq!(storage.get_all_items(), items(&[("editor.fontSize", "14")]));
+        assert_eq!(storage.get_items_with_prefix("editor."), items(&[("editor.fontSize", "14")]));
+        assert_eq!(storage.get_items_with_prefix(""), items(&[("editor.fontSize", "14")]));
+        assert!(storage.get_items(&["session".to_string(), "editor.token".to_string()]).is_empty());
+        assert_eq!(storage.get_item("session"), None);
+        assert_eq!(storage.stats().key_count, 1);
+        assert_eq!(storage.get_sealed("session").as_deref(), Some("secret"));
+    }
+}
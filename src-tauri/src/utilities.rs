@@ -2,7 +2,8 @@ use std::fs;
 use serde_json;
 use std::path::PathBuf;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::path::Path;
 use serde_json::Value;
 
 /// This function prints the type of the input value.
@@ -61,3 +62,85 @@ pub fn read_json_file(path: &PathBuf) -> Option<Value> {
         Err(_) => None, // JSON parsing error
     }
 }
+
+
+/// Writes `contents` to `path` so that readers only ever see the old or the new file, never a
+/// partial one. The data goes to a sibling temp file which is fsynced and then renamed over
+/// the destination.
+pub fn write_file_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    _write_file_atomic(path, contents, false)
+}
+
+/// Same as `write_file_atomic`, for secrets: on unix the temp file is created readable and
+/// writable by its owner only before anything is written to it, so the contents are never
+/// exposed by a permissive umask.
+pub fn write_private_file_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    _write_file_atomic(path, contents, true)
+}
+
+fn create_tmp_file(tmp_path: &Path, private: bool) -> std::io::Result<File> {
+    if !private {
+        return File::create(tmp_path);
+    }
+    // a temp file left by a crash may have other permissions, never reuse it
+    match fs::remove_file(tmp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]{
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(tmp_path)
+}
+
+fn _write_file_atomic(path: &Path, contents: &[u8], private: bool) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = create_tmp_file(&tmp_path, private)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    // make the rename itself durable. Directories can't be opened for sync on windows.
+    #[cfg(unix)]{
+        if let Some(parent) = path.parent() {
+            if let Ok(dir) = File::open(parent) {
+                let _ = dir.sync_all();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Origin of the `phtauri` protocol that serves the app. Windows webviews can't load custom
+/// schemes, so tauri maps it to https there.
+pub fn phtauri_origin() -> &'static str {
+    #[cfg(windows)]
+    return "https://phtauri.localhost";
+    #[cfg(not(windows))]
+    return "phtauri://localhost";
+}
+
+/// Milliseconds since the unix epoch, 0 if the clock is before it.
+pub fn unix_time_millis() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// A new empty directory under the system temp dir for a test. `name` has to be unique across
+/// the tests, the directory of an earlier run with the same name is removed.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("phcode-test-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
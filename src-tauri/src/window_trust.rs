@@ -0,0 +1,105 @@
+// Key exchange used to establish AES trust with a window. Rust makes an ephemeral X25519 keypair
+// per handshake, the page sends its own public key, and both sides derive the AES-256 session key
+// with HKDF-SHA256 over the shared secret:
+//   salt = page public key || native public key (32 bytes each)
+//   info = "phcode-window-trust-v1:" + window label
+// The raw key never crosses the IPC bridge, and a trust slot can't be claimed by sending a key.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const HKDF_INFO_PREFIX: &str = "phcode-window-trust-v1:";
+
+pub struct KeyExchange {
+    /// Native public key to send back to the page, hex encoded.
+    pub public_key: String,
+    pub session_key: [u8; 32],
+}
+
+/// Completes the handshake for `window_label` with the page's hex encoded X25519 public key.
+pub fn exchange_keys(window_label: &str, page_public_key: &str) -> Result<KeyExchange, String> {
+    let page_key_bytes: [u8; 32] = hex::decode(page_public_key)
+        .map_err(|_| "Invalid public key format. Key must be a valid hex string.".to_string())?
+        .try_into()
+        .map_err(|_| "Invalid public key length. Key must be 32 bytes (64 hex characters).".to_string())?;
+    let page_key = PublicKey::from(page_key_bytes);
+
+    let secret = EphemeralSecret::random();
+    let public_key = PublicKey::from(&secret);
+    let shared_secret = secret.diffie_hellman(&page_key);
+    // low order points give an all zero secret that an attacker would know too
+    if !shared_secret.was_contributory() {
+        return Err("Invalid public key.".to_string());
+    }
+
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(page_key.as_bytes());
+    salt[32..].copy_from_slice(public_key.as_bytes());
+    let info = format!("{}{}", HKDF_INFO_PREFIX, window_label);
+    let mut session_key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes())
+        .expand(info.as_bytes(), &mut session_key)
+        .map_err(|_| "Failed to derive session key".to_string())?;
+
+    Ok(KeyExchange { public_key: hex::encode(public_key.as_bytes()), session_key })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The page's side of the handshake, as the JS does it.
+    fn page_session_key(page_secret: EphemeralSecret, page_public_key: &PublicKey, native_public_key: &str,
+                        window_label: &str) -> [u8; 32] {
+        let native_key_bytes: [u8; 32] = hex::decode(native_public_key).unwrap().try_into().unwrap();
+        let shared_secret = page_secret.diffie_hellman(&PublicKey::from(native_key_bytes));
+        let mut salt = [0u8; 64];
+        salt[..32].copy_from_slice(page_public_key.as_bytes());
+        salt[32..].copy_from_slice(&native_key_bytes);
+        let mut session_key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_bytes())
+            .expand(format!("phcode-window-trust-v1:{}", window_label).as_bytes(), &mut session_key)
+            .unwrap();
+        session_key
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_key() {
+        let page_secret = EphemeralSecret::random();
+        let page_public_key = PublicKey::from(&page_secret);
+        let exchange = exchange_keys("main", &hex::encode(page_public_key.as_bytes())).unwrap();
+        assert_eq!(exchange.public_key.len(), 64);
+        assert_ne!(exchange.session_key, [0u8; 32]);
+        assert_eq!(page_session_key(page_secret, &page_public_key, &exchange.public_key, "main"), exchange.session_key);
+    }
+
+    #[test]
+    fn keys_are_bound_to_the_window_label() {
+        let page_secret = EphemeralSecret::random();
+        let page_public_key = PublicKey::from(&page_secret);
+        let exchange = exchange_keys("main", &hex::encode(page_public_key.as_bytes())).unwrap();
+        assert_ne!(page_session_key(page_secret, &page_public_key, &exchange.public_key, "phcode-1"),
+                   exchange.session_key);
+    }
+
+    #[test]
+    fn malformed_public_keys_are_rejected() {
+        assert!(exchange_keys("main", "not hex").is_err());
+        assert!(exchange_keys("main", &"ab".repeat(31)).is_err());
+        assert!(exchange_keys("main", &"ab".repeat(33)).is_err());
+        assert!(exchange_keys("main", "").is_err());
+    }
+
+    #[test]
+    fn low_order_public_keys_are_rejected() {
+        let low_order_points = [
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "0100000000000000000000000000000000000000000000000000000000000000",
+            "e0eb7a7c3b41b8ae1656e3faf19fc46ada098deb9c32b1fd866205165f49b800",
+        ];
+        for point in low_order_points {
+            assert_eq!(exchange_keys("main", point).err().as_deref(), Some("Invalid public key."));
+        }
+    }
+}